@@ -1,8 +1,26 @@
-use anyhow::Result;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use anyhow::{Context, Result};
+use log::info;
+
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::retry::{retry, RetryPolicy};
 
 const API_BASE: &str = "https://api.balena-cloud.com";
+const BUILDER_BASE: &str = "https://builder.balena-cloud.com";
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_DEVICE_STATE_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_BUILD_TIMEOUT_SECS: u64 = 3600;
+const DEFAULT_POLL_INTERVAL_MS: u64 = 2000;
+
+const DEFAULT_PAGE_SIZE: u64 = 500;
 
 #[derive(Debug, Deserialize)]
 pub struct Response<T> {
@@ -10,39 +28,706 @@ pub struct Response<T> {
     pub data: Vec<T>,
 }
 
-pub async fn get(token: &str, endpoint: &str) -> Result<reqwest::Response> {
-    let url = format!("{}/{}", API_BASE, endpoint);
-    Ok(reqwest::Client::new()
-        .get(&url)
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
-        .send()
-        .await?)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    TooManyRequests,
+    Server,
+    Other,
 }
 
-pub async fn post<T: Serialize + ?Sized>(
-    token: &str,
-    endpoint: &str,
-    json: &T,
-) -> Result<reqwest::Response> {
-    let url = format!("{}/{}", API_BASE, endpoint);
-    Ok(reqwest::Client::new()
-        .post(&url)
-        .json(json)
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
-        .send()
-        .await?)
-}
-
-pub async fn patch<T: Serialize + ?Sized>(
-    token: &str,
-    endpoint: &str,
-    json: &T,
+impl ApiErrorKind {
+    fn from_status(status: reqwest::StatusCode) -> Self {
+        match status.as_u16() {
+            401 => ApiErrorKind::Unauthorized,
+            403 => ApiErrorKind::Forbidden,
+            404 => ApiErrorKind::NotFound,
+            409 => ApiErrorKind::Conflict,
+            429 => ApiErrorKind::TooManyRequests,
+            500..=599 => ApiErrorKind::Server,
+            _ => ApiErrorKind::Other,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            ApiErrorKind::Unauthorized => "unauthorized, check the access token",
+            ApiErrorKind::Forbidden => "forbidden, the access token lacks the required permissions",
+            ApiErrorKind::NotFound => "resource not found",
+            ApiErrorKind::Conflict => "conflict with an existing resource",
+            ApiErrorKind::TooManyRequests => "too many requests, rate limit exceeded",
+            ApiErrorKind::Server => "balena server error",
+            ApiErrorKind::Other => "unexpected response",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub kind: ApiErrorKind,
+    pub status: reqwest::StatusCode,
+    pub method: reqwest::Method,
+    pub url: String,
+    pub message: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} failed with {} ({})",
+            self.method,
+            self.url,
+            self.status,
+            self.kind.description()
+        )?;
+
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+pub async fn check_status(
+    method: reqwest::Method,
+    response: reqwest::Response,
 ) -> Result<reqwest::Response> {
-    let url = format!("{}/{}", API_BASE, endpoint);
-    Ok(reqwest::Client::new()
-        .patch(&url)
-        .json(json)
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
-        .send()
-        .await?)
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let url = response.url().to_string();
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let body = response.text().await.unwrap_or_default();
+
+    Err(ApiError {
+        kind: ApiErrorKind::from_status(status),
+        status,
+        method,
+        url,
+        message: error_message(&body),
+        retry_after,
+    }
+    .into())
+}
+
+fn error_message(body: &str) -> String {
+    if let Ok(value) = serde_json::from_str::<Value>(body) {
+        for key in &["message", "error"] {
+            if let Some(message) = value.get(key).and_then(|message| message.as_str()) {
+                return message.to_string();
+            }
+        }
+    }
+
+    body.trim().to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Timeouts {
+    pub connect_secs: u64,
+    pub read_secs: u64,
+    pub device_state_secs: u64,
+    pub build_secs: u64,
+    pub poll_interval_ms: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect_secs: DEFAULT_CONNECT_TIMEOUT_SECS,
+            read_secs: DEFAULT_READ_TIMEOUT_SECS,
+            device_state_secs: DEFAULT_DEVICE_STATE_TIMEOUT_SECS,
+            build_secs: DEFAULT_BUILD_TIMEOUT_SECS,
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Tls {
+    pub ca_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiVersion {
+    /// Legacy model with `device_type` strings on applications
+    #[default]
+    V5,
+    /// Fleet model with `is_for__device_type` relations and application slugs
+    V6,
+}
+
+impl ApiVersion {
+    fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::V5 => "v5",
+            ApiVersion::V6 => "v6",
+        }
+    }
+}
+
+pub struct ClientSettings {
+    pub api_url: String,
+    pub api_version: ApiVersion,
+    pub builder_url: String,
+    pub insecure_registry: bool,
+    pub timeouts: Timeouts,
+    pub ca_file: Option<PathBuf>,
+    pub retry_policy: RetryPolicy,
+    /// Items requested per page by `get_all`
+    pub page_size: u64,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            api_url: API_BASE.to_string(),
+            api_version: Default::default(),
+            builder_url: BUILDER_BASE.to_string(),
+            insecure_registry: false,
+            timeouts: Default::default(),
+            ca_file: None,
+            retry_policy: Default::default(),
+            page_size: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+pub struct BalenaClient {
+    token: String,
+    api_base: String,
+    api_version: ApiVersion,
+    builder_base: String,
+    insecure_registry: bool,
+    read_timeout: Duration,
+    device_state_timeout: Duration,
+    build_timeout: Duration,
+    poll_interval: Duration,
+    retry_policy: RetryPolicy,
+    page_size: u64,
+    client: reqwest::Client,
+}
+
+impl BalenaClient {
+    pub fn new(token: &str, settings: ClientSettings) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(user_agent())
+            .connect_timeout(Duration::from_secs(settings.timeouts.connect_secs));
+
+        if let Some(ref ca_file) = settings.ca_file {
+            info!("Trusting CA certificate {:?}", ca_file);
+            builder = builder.add_root_certificate(read_certificate(ca_file)?);
+        }
+
+        log_proxy_environment();
+
+        let client = builder.build().context("Creating HTTP client failed")?;
+
+        Ok(BalenaClient {
+            token: token.to_string(),
+            api_base: settings.api_url.trim_end_matches('/').to_string(),
+            api_version: settings.api_version,
+            builder_base: settings.builder_url.trim_end_matches('/').to_string(),
+            insecure_registry: settings.insecure_registry,
+            read_timeout: Duration::from_secs(settings.timeouts.read_secs),
+            device_state_timeout: Duration::from_secs(settings.timeouts.device_state_secs),
+            build_timeout: Duration::from_secs(settings.timeouts.build_secs),
+            poll_interval: Duration::from_millis(settings.timeouts.poll_interval_ms),
+            retry_policy: settings.retry_policy,
+            page_size: settings.page_size.max(1),
+            client,
+        })
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn api_version(&self) -> ApiVersion {
+        self.api_version
+    }
+
+    /// Versioned endpoint of a resource, e.g. `v5/application`
+    pub fn resource(&self, name: &str) -> String {
+        format!("{}/{}", self.api_version.prefix(), name)
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn registry_url(&self, registry: &str) -> String {
+        if self.insecure_registry {
+            format!("http://{}", registry)
+        } else {
+            format!("https://{}", registry)
+        }
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    pub fn device_state_timeout(&self) -> Duration {
+        self.device_state_timeout
+    }
+
+    pub fn build_timeout(&self) -> Duration {
+        self.build_timeout
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.client.request(method, url).header(
+            reqwest::header::AUTHORIZATION,
+            format!("Bearer {}", self.token),
+        )
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let response = request.send().await?;
+        check_status(method, response).await
+    }
+
+    pub async fn get(&self, endpoint: &str) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.api_base, endpoint);
+        let request = self
+            .request(reqwest::Method::GET, &url)
+            .timeout(self.read_timeout);
+        self.send(reqwest::Method::GET, request).await
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T> {
+        retry(
+            &self.retry_policy,
+            &format!("GET {}", endpoint),
+            || async move { Ok(self.get(endpoint).await?.json::<T>().await?) },
+        )
+        .await
+    }
+
+    pub async fn get_all<T: DeserializeOwned>(&self, query: &Query) -> Result<Vec<T>> {
+        let query = if query.orderby.is_empty() {
+            query.clone().orderby("id", Order::Asc)
+        } else {
+            query.clone()
+        };
+
+        let mut items = Vec::new();
+        let mut skip = 0;
+
+        loop {
+            let page = query.clone().top(self.page_size).skip(skip);
+            let mut data = self.get_json::<Response<T>>(&page.endpoint()).await?.data;
+            let count = data.len() as u64;
+
+            items.append(&mut data);
+
+            if count < self.page_size {
+                return Ok(items);
+            }
+
+            skip += count;
+        }
+    }
+
+    pub async fn post<T: Serialize + ?Sized>(
+        &self,
+        endpoint: &str,
+        json: &T,
+    ) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.api_base, endpoint);
+        let request = self
+            .request(reqwest::Method::POST, &url)
+            .timeout(self.read_timeout)
+            .json(json);
+        self.send(reqwest::Method::POST, request).await
+    }
+
+    pub async fn patch<T: Serialize + ?Sized>(
+        &self,
+        endpoint: &str,
+        json: &T,
+    ) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.api_base, endpoint);
+        let request = self
+            .request(reqwest::Method::PATCH, &url)
+            .timeout(self.read_timeout)
+            .json(json);
+        self.send(reqwest::Method::PATCH, request).await
+    }
+
+    pub async fn delete(&self, endpoint: &str) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.api_base, endpoint);
+        let request = self
+            .request(reqwest::Method::DELETE, &url)
+            .timeout(self.read_timeout);
+        self.send(reqwest::Method::DELETE, request).await
+    }
+
+    pub async fn post_build(&self, endpoint: &str, gzip: Vec<u8>) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.builder_base, endpoint);
+        let request = self
+            .request(reqwest::Method::POST, &url)
+            .header(reqwest::header::CONTENT_ENCODING, "gzip")
+            .body(gzip);
+        self.send(reqwest::Method::POST, request).await
+    }
+}
+
+fn user_agent() -> String {
+    format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+fn read_certificate(path: &Path) -> Result<reqwest::Certificate> {
+    let pem = std::fs::read(path).context(format!("Reading CA certificate {:?} failed", path))?;
+    reqwest::Certificate::from_pem(&pem)
+        .context(format!("Parsing CA certificate {:?} failed", path))
+}
+
+// Proxies are picked up by reqwest itself from HTTP_PROXY, HTTPS_PROXY and NO_PROXY
+fn log_proxy_environment() {
+    for name in &["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"] {
+        if let Ok(proxy) = std::env::var(name) {
+            info!("Using proxy {} from {}", proxy, name);
+            return;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone)]
+pub enum Literal {
+    String(String),
+    Integer(u64),
+    Boolean(bool),
+}
+
+impl From<&str> for Literal {
+    fn from(value: &str) -> Self {
+        Literal::String(value.to_string())
+    }
+}
+
+impl From<u64> for Literal {
+    fn from(value: u64) -> Self {
+        Literal::Integer(value)
+    }
+}
+
+impl From<bool> for Literal {
+    fn from(value: bool) -> Self {
+        Literal::Boolean(value)
+    }
+}
+
+impl Literal {
+    fn render(&self) -> String {
+        match self {
+            Literal::String(value) => format!("'{}'", value.replace('\'', "''")),
+            Literal::Integer(value) => value.to_string(),
+            Literal::Boolean(value) => value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Filter {
+    Eq(String, Literal),
+    And(Vec<Filter>),
+}
+
+impl Filter {
+    pub fn eq<L: Into<Literal>>(field: &str, value: L) -> Self {
+        Filter::Eq(field.to_string(), value.into())
+    }
+
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    fn render(&self) -> String {
+        match self {
+            Filter::Eq(field, literal) => format!("{} eq {}", field, literal.render()),
+            Filter::And(filters) => filters
+                .iter()
+                .map(|filter| match filter {
+                    Filter::And(_) => format!("({})", filter.render()),
+                    _ => filter.render(),
+                })
+                .collect::<Vec<_>>()
+                .join(" and "),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    resource: String,
+    filter: Option<Filter>,
+    select: Vec<String>,
+    expand: Vec<(String, Query)>,
+    top: Option<u64>,
+    skip: Option<u64>,
+    orderby: Vec<(String, Order)>,
+    params: Vec<(String, String)>,
+}
+
+impl Query {
+    pub fn new(resource: &str) -> Self {
+        Query {
+            resource: resource.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn nested() -> Self {
+        Default::default()
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(match self.filter.take() {
+            Some(existing) => existing.and(filter),
+            None => filter,
+        });
+        self
+    }
+
+    pub fn select(mut self, fields: &[&str]) -> Self {
+        self.select
+            .extend(fields.iter().map(|field| field.to_string()));
+        self
+    }
+
+    pub fn expand(mut self, field: &str, query: Query) -> Self {
+        self.expand.push((field.to_string(), query));
+        self
+    }
+
+    pub fn top(mut self, top: u64) -> Self {
+        self.top = Some(top);
+        self
+    }
+
+    pub fn skip(mut self, skip: u64) -> Self {
+        self.skip = Some(skip);
+        self
+    }
+
+    pub fn orderby(mut self, field: &str, order: Order) -> Self {
+        self.orderby.push((field.to_string(), order));
+        self
+    }
+
+    /// Plain query parameter for endpoints outside of the OData model, such as the builder
+    pub fn param<V: ToString>(mut self, key: &str, value: V) -> Self {
+        self.params.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn endpoint(&self) -> String {
+        let options = self.options();
+
+        let encoded = options
+            .iter()
+            .map(|(key, value)| (*key, value))
+            .chain(self.params.iter().map(|(key, value)| (key.as_str(), value)))
+            .map(|(key, value)| format!("{}={}", key, percent_encode(value)))
+            .collect::<Vec<_>>();
+
+        if encoded.is_empty() {
+            return self.resource.clone();
+        }
+
+        format!("{}?{}", self.resource, encoded.join("&"))
+    }
+
+    fn options(&self) -> Vec<(&'static str, String)> {
+        let mut options = Vec::new();
+
+        if let Some(ref filter) = self.filter {
+            options.push(("$filter", filter.render()));
+        }
+
+        if !self.select.is_empty() {
+            options.push(("$select", self.select.join(",")));
+        }
+
+        if !self.expand.is_empty() {
+            let expand = self
+                .expand
+                .iter()
+                .map(|(field, query)| {
+                    let nested = query
+                        .options()
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<_>>()
+                        .join(";");
+                    if nested.is_empty() {
+                        field.clone()
+                    } else {
+                        format!("{}({})", field, nested)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            options.push(("$expand", expand));
+        }
+
+        if let Some(top) = self.top {
+            options.push(("$top", top.to_string()));
+        }
+
+        if let Some(skip) = self.skip {
+            if skip > 0 {
+                options.push(("$skip", skip.to_string()));
+            }
+        }
+
+        if !self.orderby.is_empty() {
+            let orderby = self
+                .orderby
+                .iter()
+                .map(|(field, order)| match order {
+                    Order::Asc => format!("{} asc", field),
+                    Order::Desc => format!("{} desc", field),
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            options.push(("$orderby", orderby));
+        }
+
+        options
+    }
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_quotes_in_string_literals() {
+        assert_eq!(Literal::from("O'Brien's").render(), "'O''Brien''s'");
+        assert_eq!(Literal::from("").render(), "''");
+        assert_eq!(Literal::from(42).render(), "42");
+        assert_eq!(Literal::from(true).render(), "true");
+    }
+
+    #[test]
+    fn percent_encodes_reserved_and_non_ascii_bytes() {
+        assert_eq!(percent_encode("AZaz09-._~"), "AZaz09-._~");
+        assert_eq!(percent_encode("a b&c=d"), "a%20b%26c%3Dd");
+        assert_eq!(
+            percent_encode("$filter/'x'+(y)"),
+            "%24filter%2F%27x%27%2B%28y%29"
+        );
+        assert_eq!(percent_encode("ü"), "%C3%BC");
+    }
+
+    #[test]
+    fn renders_nested_expand_options() {
+        let query = Query::new("v6/release")
+            .filter(Filter::eq("id", 7))
+            .select(&["id", "status"])
+            .expand(
+                "contains__image",
+                Query::nested().expand(
+                    "image",
+                    Query::nested()
+                        .select(&["build_log", "content_hash"])
+                        .expand(
+                            "is_a_build_of__service",
+                            Query::nested().select(&["service_name"]),
+                        ),
+                ),
+            )
+            .expand("belongs_to__application", Query::nested());
+
+        assert_eq!(
+            query.options(),
+            vec![
+                ("$filter", "id eq 7".to_string()),
+                ("$select", "id,status".to_string()),
+                (
+                    "$expand",
+                    "contains__image($expand=image($select=build_log,content_hash;\
+                     $expand=is_a_build_of__service($select=service_name))),\
+                     belongs_to__application"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_filter_and_plain_params_in_endpoint() {
+        let endpoint = Query::new("v6/application")
+            .filter(Filter::eq("app_name", "my app").and(Filter::eq("is_host", false)))
+            .top(2)
+            .param("dockerfilePath", "sub dir/Dockerfile")
+            .endpoint();
+
+        assert_eq!(
+            endpoint,
+            "v6/application?$filter=app_name%20eq%20%27my%20app%27%20and%20is_host%20eq%20false\
+             &$top=2&dockerfilePath=sub%20dir%2FDockerfile"
+        );
+    }
 }
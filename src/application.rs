@@ -1,65 +1,154 @@
-use anyhow::{anyhow, Context, Result};
-use log::info;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::api::{get, post, Response};
+use crate::api::{ApiVersion, BalenaClient, Filter, Query, Response};
+use crate::device::get_application_devices;
+use crate::organization::Organization;
+use crate::tag::{get_application_tags, tag_managed_application, TAG_CONFIG, TAG_MANAGED};
+use crate::user::User;
 
-const ENDPOINT_APPLICATION: &str = "v5/application";
+const RESOURCE_APPLICATION: &str = "application";
+const RESOURCE_DEVICE_TYPE: &str = "device_type";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct Application {
     pub id: u64,
-    #[serde(rename = "app_name")]
     pub name: String,
     pub device_type: String,
+    pub slug: Option<String>,
+}
+
+impl Application {
+    /// Owner handle from the `<owner>/<name>` application slug
+    pub fn owner(&self) -> Option<&str> {
+        self.slug
+            .as_ref()
+            .and_then(|slug| slug.split('/').next())
+            .filter(|owner| !owner.is_empty())
+    }
 }
 
 #[derive(Debug, Deserialize)]
-struct ApplicationUsers {
+struct ApplicationData {
     id: u64,
-    user: Vec<User>,
+    app_name: String,
+    #[serde(default)]
+    slug: Option<String>,
+    #[serde(default)]
+    device_type: Option<String>,
+    #[serde(default, rename = "is_for__device_type")]
+    is_for_device_type: Option<Value>,
+}
+
+impl ApplicationData {
+    fn into_application(self, default_device_type: Option<&str>) -> Result<Application> {
+        // v6 returns the device type relation as an expanded array when requested
+        let expanded_device_type = self
+            .is_for_device_type
+            .as_ref()
+            .and_then(|device_types| device_types.get(0))
+            .and_then(|device_type| device_type.get("slug"))
+            .and_then(|slug| slug.as_str())
+            .map(|slug| slug.to_string());
+
+        let device_type = self
+            .device_type
+            .or(expanded_device_type)
+            .or_else(|| default_device_type.map(|device_type| device_type.to_string()))
+            .context(format!(
+                "No device type found for application '{}'",
+                self.app_name
+            ))?;
+
+        Ok(Application {
+            id: self.id,
+            name: self.app_name,
+            device_type,
+            slug: self.slug,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
-pub struct User {
-    pub id: u64,
-    pub username: String,
+struct DeviceType {
+    id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplicationUsers {
+    user: Vec<User>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct CreateApplicationRequest {
     #[serde(rename = "app_name")]
     pub name: String,
-    pub device_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<String>,
+    #[serde(
+        rename = "is_for__device_type",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub device_type_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organization: Option<u64>,
 }
 
-fn get_application_by_name_endpoint(name: &str) -> String {
-    format!("{}?$filter=app_name eq '{}'", ENDPOINT_APPLICATION, name)
+fn get_application_query(client: &BalenaClient) -> Query {
+    let query = Query::new(&client.resource(RESOURCE_APPLICATION));
+
+    match client.api_version() {
+        ApiVersion::V5 => query,
+        ApiVersion::V6 => query.expand("is_for__device_type", Query::nested().select(&["slug"])),
+    }
+}
+
+async fn get_first_application(
+    client: &BalenaClient,
+    endpoint: &str,
+) -> Result<Option<Application>> {
+    client
+        .get_json::<Response<ApplicationData>>(endpoint)
+        .await?
+        .data
+        .pop()
+        .map(|data| data.into_application(None))
+        .transpose()
+}
+
+fn get_application_by_name_endpoint(
+    client: &BalenaClient,
+    name: &str,
+    organization: Option<&Organization>,
+) -> String {
+    let query = get_application_query(client).filter(Filter::eq("app_name", name));
+
+    if let Some(organization) = organization {
+        query
+            .filter(Filter::eq("organization", organization.id))
+            .endpoint()
+    } else {
+        query.endpoint()
+    }
 }
 
 pub async fn get_application_by_name(
-    token: &str,
+    client: &BalenaClient,
     name: &str,
-    device_type: &str,
+    organization: Option<&Organization>,
 ) -> Result<Option<Application>> {
     info!("Getting application by name '{}'", name);
 
-    let application_option = get(token, &get_application_by_name_endpoint(name))
-        .await?
-        .json::<Response<Application>>()
-        .await?
-        .data
-        .pop();
+    let application_option = get_first_application(
+        client,
+        &get_application_by_name_endpoint(client, name, organization),
+    )
+    .await?;
 
     if let Some(ref application) = application_option {
-        if application.device_type != device_type {
-            anyhow!(
-                "Device type does not match (expected: {} / real: {})",
-                device_type,
-                application.device_type
-            );
-        }
         info!(
             "Application found '{}' ({})",
             application.name, application.id
@@ -71,12 +160,34 @@ pub async fn get_application_by_name(
     Ok(application_option)
 }
 
-pub async fn get_application_user(token: &str, application: &Application) -> Result<User> {
+fn get_application_by_id_endpoint(client: &BalenaClient, application_id: u64) -> String {
+    get_application_query(client)
+        .filter(Filter::eq("id", application_id))
+        .endpoint()
+}
+
+pub async fn get_application_by_id(
+    client: &BalenaClient,
+    application_id: u64,
+) -> Result<Option<Application>> {
+    get_first_application(
+        client,
+        &get_application_by_id_endpoint(client, application_id),
+    )
+    .await
+}
+
+pub async fn get_application_user(
+    client: &BalenaClient,
+    application: &Application,
+) -> Result<User> {
     info!("Getting '{}' user", application.name);
 
-    let mut response = get(token, &get_application_user_endpoint(&application.name))
-        .await?
-        .json::<Response<ApplicationUsers>>()
+    let mut response = client
+        .get_json::<Response<ApplicationUsers>>(&get_application_user_endpoint(
+            client,
+            application.id,
+        ))
         .await?
         .data;
 
@@ -92,46 +203,179 @@ pub async fn get_application_user(token: &str, application: &Application) -> Res
     Ok(user)
 }
 
-fn get_application_user_endpoint(name: &str) -> String {
-    format!(
-        "{}?$expand=user($select=id,username)&$filter=app_name eq '{}'&$select=id",
-        ENDPOINT_APPLICATION, name
-    )
+fn get_application_user_endpoint(client: &BalenaClient, application_id: u64) -> String {
+    Query::new(&client.resource(RESOURCE_APPLICATION))
+        .expand("user", Query::nested().select(&["id", "username"]))
+        .filter(Filter::eq("id", application_id))
+        .select(&["id"])
+        .endpoint()
 }
 
-pub async fn create_application(token: &str, name: &str, device_type: &str) -> Result<Application> {
-    info!("Creating application '{}'", name);
+fn get_device_type_by_slug_endpoint(client: &BalenaClient, slug: &str) -> String {
+    Query::new(&client.resource(RESOURCE_DEVICE_TYPE))
+        .filter(Filter::eq("slug", slug))
+        .select(&["id"])
+        .endpoint()
+}
+
+async fn get_device_type_id(client: &BalenaClient, slug: &str) -> Result<u64> {
+    info!("Getting device type '{}'", slug);
+
+    Ok(client
+        .get_json::<Response<DeviceType>>(&get_device_type_by_slug_endpoint(client, slug))
+        .await?
+        .data
+        .pop()
+        .context(format!("Device type '{}' not found", slug))?
+        .id)
+}
+
+pub async fn create_application(
+    client: &BalenaClient,
+    name: &str,
+    device_type: &str,
+    organization: Option<&Organization>,
+    config_hash: &str,
+) -> Result<Application> {
+    if let Some(organization) = organization {
+        info!(
+            "Creating application '{}' in organization '{}'",
+            name, organization.handle
+        );
+    } else {
+        info!("Creating application '{}'", name);
+    }
+
+    let (device_type_string, device_type_id) = match client.api_version() {
+        ApiVersion::V5 => (Some(device_type.to_string()), None),
+        ApiVersion::V6 => (None, Some(get_device_type_id(client, device_type).await?)),
+    };
 
     let input = CreateApplicationRequest {
         name: name.to_string(),
-        device_type: device_type.to_string(),
+        device_type: device_type_string,
+        device_type_id,
+        organization: organization.map(|organization| organization.id),
     };
 
-    let application = post(token, ENDPOINT_APPLICATION, &input)
+    let application = client
+        .post(&client.resource(RESOURCE_APPLICATION), &input)
+        .await
+        .context(format!("Creating application '{}' failed", name))?
+        .json::<ApplicationData>()
         .await?
-        .json::<Application>()
-        .await?;
+        .into_application(Some(device_type))?;
 
     info!(
         "Application '{}' created ({})",
         application.name, application.id
     );
 
+    tag_managed_application(client, application.id, config_hash).await?;
+
     Ok(application)
 }
 
+fn get_application_id_endpoint(client: &BalenaClient, application_id: u64) -> String {
+    format!(
+        "{}({})",
+        client.resource(RESOURCE_APPLICATION),
+        application_id
+    )
+}
+
+pub async fn delete_application(client: &BalenaClient, application: &Application) -> Result<()> {
+    info!(
+        "Deleting application '{}' ({})",
+        application.name, application.id
+    );
+
+    client
+        .delete(&get_application_id_endpoint(client, application.id))
+        .await
+        .context(format!(
+            "Deleting application '{}' failed",
+            application.name
+        ))?;
+
+    Ok(())
+}
+
 pub async fn get_or_create_application(
-    token: &str,
+    client: &BalenaClient,
     name: &str,
     device_type: &str,
+    slug: &str,
+    organization: Option<&Organization>,
+    config_hash: &str,
+    recreate_mismatched: bool,
 ) -> Result<Application> {
-    let application_option = get_application_by_name(token, name, device_type).await?;
+    let application_option = get_application_by_name(client, name, organization).await?;
 
-    let application = if let Some(application) = application_option {
-        application
-    } else {
-        create_application(token, name, device_type).await?
+    if let Some(ref application) = application_option {
+        ensure_managed_application(client, application, slug, config_hash).await?;
+    }
+
+    let application = match application_option {
+        Some(application) if application.device_type != device_type => {
+            if !recreate_mismatched {
+                bail!(
+                    "Application '{}' device type does not match (expected: {} / real: {}), \
+                     use --recreate-mismatched to recreate it",
+                    application.name,
+                    device_type,
+                    application.device_type
+                );
+            }
+
+            warn!(
+                "Application '{}' device type does not match (expected: {} / real: {})",
+                application.name, device_type, application.device_type
+            );
+
+            delete_application(client, &application).await?;
+
+            create_application(client, name, device_type, organization, config_hash).await?
+        }
+        Some(application) => application,
+        None => create_application(client, name, device_type, organization, config_hash).await?,
     };
 
     Ok(application)
 }
+
+async fn ensure_managed_application(
+    client: &BalenaClient,
+    application: &Application,
+    slug: &str,
+    config_hash: &str,
+) -> Result<()> {
+    let tags = get_application_tags(client, application.id).await?;
+
+    if !tags.contains_key(TAG_MANAGED) {
+        // Applications created before tagging was introduced only hold the placeholder device
+        let devices = get_application_devices(client, application).await?;
+
+        if devices.is_empty() || devices.iter().any(|device| device.name != slug) {
+            bail!(
+                "Application '{}' is not managed by {}, \
+                 set a distinct 'project' or 'application_name'",
+                application.name,
+                env!("CARGO_PKG_NAME")
+            );
+        }
+
+        info!("Adopting untagged application '{}'", application.name);
+
+        return tag_managed_application(client, application.id, config_hash).await;
+    }
+
+    if tags.get(TAG_CONFIG).map(String::as_str) != Some(config_hash) {
+        warn!(
+            "Application '{}' was created from a different config file",
+            application.name
+        );
+    }
+
+    Ok(())
+}
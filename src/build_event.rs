@@ -0,0 +1,575 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use serde_json::{Deserializer, Map, Value};
+
+const RESOURCE_CURSOR: &str = "cursor";
+const RESOURCE_IMAGE: &str = "image";
+const CURSOR_ERASE: &str = "erase";
+
+// Bytes of input quoted in malformed stream errors
+const MALFORMED_CONTEXT_LENGTH: usize = 40;
+
+/// Event reported by the remote builder while streaming a build
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildEvent {
+    /// Output line
+    Message(String),
+    /// Output line overwriting the previous one, as used for progress
+    Replace(String),
+    /// Removes the previous output line
+    Erase,
+    /// Image of a service pushed to the registry
+    ImagePushed { service: String, location: String },
+    /// Final result, optionally with a closing message
+    Finished {
+        success: bool,
+        message: Option<String>,
+    },
+    /// Event not known to this version, kept as received
+    Unknown(Value),
+}
+
+impl BuildEvent {
+    pub fn from_value(value: Value) -> Result<Self> {
+        let object = value
+            .as_object()
+            .context("Serialized response is not an object")?;
+
+        if let Some(is_success) = object.get("isSuccess") {
+            let success = is_success
+                .as_bool()
+                .context("Message isSuccess property is not a boolean")?;
+            let message = get_message(object)?.map(|message| message.to_string());
+            return Ok(BuildEvent::Finished { success, message });
+        }
+
+        if let Some(message) = get_message(object)? {
+            let replace = match object.get("replace") {
+                Some(replace) => replace
+                    .as_bool()
+                    .context("Message replace property is not a boolean")?,
+                None => false,
+            };
+            return Ok(if replace {
+                BuildEvent::Replace(message.to_string())
+            } else {
+                BuildEvent::Message(message.to_string())
+            });
+        }
+
+        if let Some(resource) = object.get("resource") {
+            let resource = resource
+                .as_str()
+                .context("Resource property is not a string")?;
+            let resource_value = object.get("value");
+
+            match resource {
+                RESOURCE_CURSOR if resource_value == Some(&Value::from(CURSOR_ERASE)) => {
+                    return Ok(BuildEvent::Erase);
+                }
+                RESOURCE_IMAGE => {
+                    let service = resource_value.and_then(|image| image["service"].as_str());
+                    let location = resource_value.and_then(|image| image["location"].as_str());
+                    if let (Some(service), Some(location)) = (service, location) {
+                        return Ok(BuildEvent::ImagePushed {
+                            service: service.to_string(),
+                            location: location.to_string(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(BuildEvent::Unknown(value))
+    }
+
+    /// Line a renderer prints for the event, if it prints one at all
+    pub fn text(&self) -> Option<String> {
+        match self {
+            BuildEvent::Message(message) | BuildEvent::Replace(message) => Some(message.clone()),
+            BuildEvent::ImagePushed { service, location } => {
+                Some(format!("Pushed '{}' image {}", service, location))
+            }
+            BuildEvent::Finished { message, .. } => message.clone(),
+            BuildEvent::Erase | BuildEvent::Unknown(_) => None,
+        }
+    }
+}
+
+fn get_message(object: &Map<String, Value>) -> Result<Option<&str>> {
+    match object.get("message") {
+        Some(message) => Ok(Some(
+            message
+                .as_str()
+                .context("Response message is not a string")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Consumer of the events of remote builds, such as the terminal renderer
+///
+/// Events of every build attempt are passed on in order, followed by `finish`
+/// once the attempt ends. Headless builds report the build logs of their
+/// release as `Message` events and their result as a `Finished` event.
+pub trait BuildEventSink: Send {
+    fn event(&mut self, event: &BuildEvent) -> Result<()>;
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Ignores all events
+impl BuildEventSink for () {
+    fn event(&mut self, _event: &BuildEvent) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: BuildEventSink + ?Sized> BuildEventSink for &mut S {
+    fn event(&mut self, event: &BuildEvent) -> Result<()> {
+        (**self).event(event)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Passes every event to both sinks in turn
+impl<A: BuildEventSink, B: BuildEventSink> BuildEventSink for (A, B) {
+    fn event(&mut self, event: &BuildEvent) -> Result<()> {
+        self.0.event(event)?;
+        self.1.event(event)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.0.finish()?;
+        self.1.finish()
+    }
+}
+
+/// Build events read from a streamed builder response
+pub struct BuildEventStream {
+    response: reqwest::Response,
+    read_timeout: Duration,
+    values: ArrayStream,
+}
+
+impl BuildEventStream {
+    pub fn new(response: reqwest::Response, read_timeout: Duration) -> Self {
+        BuildEventStream {
+            response,
+            read_timeout,
+            values: ArrayStream::new(),
+        }
+    }
+
+    /// Next event, or `None` once the builder closes the stream
+    pub async fn next(&mut self) -> Result<Option<BuildEvent>> {
+        loop {
+            if let Some(value) = self.values.next_value()? {
+                return BuildEvent::from_value(value).map(Some);
+            }
+
+            let chunk = tokio::time::timeout(self.read_timeout, self.response.chunk())
+                .await
+                .context("Timed out waiting for build output")??;
+
+            match chunk {
+                Some(chunk) => self.values.extend(&chunk),
+                None => {
+                    self.values.finish()?;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArrayState {
+    Start,
+    First,
+    Value,
+    Separator,
+    End,
+}
+
+/// Incremental parser of a JSON array received in arbitrary byte chunks
+///
+/// Values are only parsed once complete, so chunks may split them anywhere,
+/// including inside multi-byte UTF-8 sequences.
+struct ArrayStream {
+    buffer: Vec<u8>,
+    state: ArrayState,
+    // Stream offset of the buffer start, for error context
+    offset: usize,
+}
+
+impl ArrayStream {
+    fn new() -> Self {
+        ArrayStream {
+            buffer: Vec::new(),
+            state: ArrayState::Start,
+            offset: 0,
+        }
+    }
+
+    fn extend(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Next complete value, or `None` until more input arrives
+    fn next_value(&mut self) -> Result<Option<Value>> {
+        loop {
+            self.skip_whitespace();
+
+            let byte = match self.buffer.first() {
+                Some(&byte) => byte,
+                None => return Ok(None),
+            };
+
+            match (self.state, byte) {
+                (ArrayState::Start, b'[') => self.advance(1, ArrayState::First),
+                (ArrayState::Start, _) => return Err(self.malformed("expected '['")),
+                (ArrayState::First, b']') | (ArrayState::Separator, b']') => {
+                    self.advance(1, ArrayState::End)
+                }
+                (ArrayState::Separator, b',') => self.advance(1, ArrayState::Value),
+                (ArrayState::Separator, _) => return Err(self.malformed("expected ',' or ']'")),
+                (ArrayState::First, _) | (ArrayState::Value, _) => return self.parse_value(),
+                (ArrayState::End, _) => {
+                    return Err(self.malformed("unexpected data after the array"))
+                }
+            }
+        }
+    }
+
+    /// Checks that the input ended with a complete array
+    fn finish(&mut self) -> Result<()> {
+        self.skip_whitespace();
+
+        match (self.state, self.buffer.is_empty()) {
+            (ArrayState::End, true) => Ok(()),
+            (ArrayState::End, false) => Err(self.malformed("unexpected data after the array")),
+            _ => Err(self.malformed("unexpected end of input")),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Option<Value>> {
+        let mut values = Deserializer::from_slice(&self.buffer).into_iter::<Value>();
+
+        match values.next() {
+            // A number at the very end of the input may continue in the next chunk
+            Some(Ok(Value::Number(_))) if values.byte_offset() == self.buffer.len() => Ok(None),
+            Some(Ok(value)) => {
+                let length = values.byte_offset();
+                self.advance(length, ArrayState::Separator);
+                Ok(Some(value))
+            }
+            Some(Err(error)) if error.is_eof() => Ok(None),
+            Some(Err(error)) => Err(self.malformed(&error.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let whitespace = self
+            .buffer
+            .iter()
+            .take_while(|byte| matches!(byte, b' ' | b'\t' | b'\n' | b'\r'))
+            .count();
+        self.buffer.drain(..whitespace);
+        self.offset += whitespace;
+    }
+
+    fn advance(&mut self, length: usize, state: ArrayState) {
+        self.buffer.drain(..length);
+        self.offset += length;
+        self.state = state;
+    }
+
+    fn malformed(&self, reason: &str) -> anyhow::Error {
+        let context = &self.buffer[..self.buffer.len().min(MALFORMED_CONTEXT_LENGTH)];
+        anyhow!(
+            "Malformed build stream at byte {}: {} (near {:?})",
+            self.offset,
+            reason,
+            String::from_utf8_lossy(context)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn parse_chunks(chunks: &[&[u8]]) -> Result<Vec<Value>> {
+        let mut stream = ArrayStream::new();
+        let mut values = Vec::new();
+
+        for chunk in chunks {
+            stream.extend(chunk);
+            while let Some(value) = stream.next_value()? {
+                values.push(value);
+            }
+        }
+
+        stream.finish()?;
+
+        Ok(values)
+    }
+
+    fn parse_error(input: &str) -> String {
+        parse_chunks(&[input.as_bytes()]).unwrap_err().to_string()
+    }
+
+    // Deterministic xorshift generator, so failures are reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+    }
+
+    fn random_value(rng: &mut Rng, depth: usize) -> Value {
+        const CHARS: &[char] = &['a', ' ', '"', '\\', '\n', 'é', '✓', '🚀', '\u{1b}'];
+
+        match rng.below(if depth > 2 { 4 } else { 6 }) {
+            0 => Value::Null,
+            1 => json!(rng.below(2) == 0),
+            2 => json!(rng.next() as i64 / (1 + rng.below(1000) as i64)),
+            3 => (0..rng.below(12))
+                .map(|_| CHARS[rng.below(CHARS.len())])
+                .collect::<String>()
+                .into(),
+            4 => (0..rng.below(4))
+                .map(|_| random_value(rng, depth + 1))
+                .collect::<Vec<_>>()
+                .into(),
+            _ => (0..rng.below(4))
+                .map(|index| (format!("key{}", index), random_value(rng, depth + 1)))
+                .collect::<Map<String, Value>>()
+                .into(),
+        }
+    }
+
+    fn random_chunks<'a>(rng: &mut Rng, input: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        let mut rest = input;
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at(1 + rng.below(rest.len().min(16)));
+            chunks.push(chunk);
+            rest = tail;
+        }
+        chunks
+    }
+
+    #[test]
+    fn parses_typed_build_events() {
+        let events = vec![
+            json!({ "message": "Step 1/2" }),
+            json!({ "message": "Step 2/2", "replace": true }),
+            json!({ "resource": "cursor", "value": "erase" }),
+            json!({ "resource": "image", "value": { "service": "main", "location": "r/i" } }),
+            json!({ "isSuccess": false, "message": "Build failed" }),
+            json!({ "type": "metadata", "resource": "phase", "value": "push" }),
+        ];
+
+        let parsed = events
+            .into_iter()
+            .map(|event| BuildEvent::from_value(event).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            parsed,
+            vec![
+                BuildEvent::Message("Step 1/2".to_string()),
+                BuildEvent::Replace("Step 2/2".to_string()),
+                BuildEvent::Erase,
+                BuildEvent::ImagePushed {
+                    service: "main".to_string(),
+                    location: "r/i".to_string(),
+                },
+                BuildEvent::Finished {
+                    success: false,
+                    message: Some("Build failed".to_string()),
+                },
+                BuildEvent::Unknown(
+                    json!({ "type": "metadata", "resource": "phase", "value": "push" })
+                ),
+            ]
+        );
+
+        assert!(BuildEvent::from_value(json!({ "message": 1 })).is_err());
+    }
+
+    #[test]
+    fn renders_event_text() {
+        let pushed = BuildEvent::ImagePushed {
+            service: "main".to_string(),
+            location: "r/i".to_string(),
+        };
+        assert_eq!(pushed.text().unwrap(), "Pushed 'main' image r/i");
+
+        let finished = BuildEvent::Finished {
+            success: true,
+            message: None,
+        };
+        assert_eq!(finished.text(), None);
+        assert_eq!(BuildEvent::Erase.text(), None);
+    }
+
+    #[test]
+    fn parses_values_split_at_every_boundary() {
+        let input = "[{\"message\":\"héllo ✓ 🚀\"},\n{\"isSuccess\":true,\"n\":12345}]";
+        let expected = vec![
+            json!({ "message": "héllo ✓ 🚀" }),
+            json!({ "isSuccess": true, "n": 12345 }),
+        ];
+
+        let bytes = input.as_bytes();
+        for split in 0..=bytes.len() {
+            let (head, tail) = bytes.split_at(split);
+            assert_eq!(
+                parse_chunks(&[head, tail]).unwrap(),
+                expected,
+                "split {}",
+                split
+            );
+        }
+
+        let single_bytes = bytes.chunks(1).collect::<Vec<_>>();
+        assert_eq!(parse_chunks(&single_bytes).unwrap(), expected);
+    }
+
+    #[test]
+    fn waits_for_numbers_continuing_in_next_chunk() {
+        assert_eq!(
+            parse_chunks(&[b"[1", b"23, -4", b".5]"]).unwrap(),
+            vec![json!(123), json!(-4.5)]
+        );
+    }
+
+    #[test]
+    fn parses_empty_array_with_whitespace() {
+        assert!(parse_chunks(&[b" \r\n[ ", b"\t]\n"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_malformed_input_with_context() {
+        let error = parse_error("[{\"a\":1} {\"b\":2}]");
+        assert!(
+            error.contains("at byte 9: expected ',' or ']'"),
+            "{}",
+            error
+        );
+        assert!(error.contains("near \"{\\\"b\\\":2}]\""), "{}", error);
+
+        let error = parse_error("[{\"a\":1},]");
+        assert!(error.contains("at byte 9"), "{}", error);
+
+        let error = parse_error("{\"a\":1}");
+        assert!(error.contains("at byte 0: expected '['"), "{}", error);
+
+        let error = parse_error("[1, \"a\u{1}\"]");
+        assert!(error.contains("at byte 4: control character"), "{}", error);
+    }
+
+    #[test]
+    fn reports_invalid_utf8() {
+        let error = parse_chunks(&[b"[\"a", b"\xff\xfe\"]"])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("at byte 1"), "{}", error);
+    }
+
+    #[test]
+    fn reports_truncated_and_trailing_input() {
+        let error = parse_error("[{\"a\":1},");
+        assert!(error.contains("unexpected end of input"), "{}", error);
+
+        let error = parse_error("[{\"a\":");
+        assert!(error.contains("unexpected end of input"), "{}", error);
+
+        let error = parse_error("[1] [2]");
+        assert!(
+            error.contains("at byte 4: unexpected data after the array"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn round_trips_random_values_in_random_chunks() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..500 {
+            let values = (0..rng.below(8))
+                .map(|_| random_value(&mut rng, 0))
+                .collect::<Vec<_>>();
+            let input = match rng.below(2) {
+                0 => serde_json::to_vec(&values).unwrap(),
+                _ => serde_json::to_vec_pretty(&values).unwrap(),
+            };
+
+            let chunks = random_chunks(&mut rng, &input);
+            assert_eq!(parse_chunks(&chunks).unwrap(), values);
+        }
+    }
+
+    #[test]
+    fn chunking_does_not_change_result_of_corrupted_input() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..2000 {
+            let values = (0..1 + rng.below(4))
+                .map(|_| random_value(&mut rng, 0))
+                .collect::<Vec<_>>();
+            let mut input = serde_json::to_vec(&values).unwrap();
+
+            for _ in 0..1 + rng.below(3) {
+                let position = rng.below(input.len());
+                match rng.below(3) {
+                    0 => input[position] = rng.next() as u8,
+                    1 => {
+                        input.remove(position);
+                    }
+                    _ => input.truncate(position),
+                }
+                if input.is_empty() {
+                    break;
+                }
+            }
+
+            let whole = parse_chunks(&[&input]);
+            let chunked = parse_chunks(&random_chunks(&mut rng, &input));
+
+            match (whole, chunked) {
+                (Ok(whole), Ok(chunked)) => assert_eq!(whole, chunked),
+                (Err(_), Err(_)) => {}
+                (whole, chunked) => panic!(
+                    "{:?} parsed differently: {:?} / {:?}",
+                    String::from_utf8_lossy(&input),
+                    whole,
+                    chunked
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+
+use crate::build_event::{BuildEvent, BuildEventSink};
+use crate::terminal::strip_ansi;
+
+/// Build output of a target as it ends up on a terminal, saved to `<dir>/<slug>.log`
+pub struct BuildLog {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl BuildLog {
+    pub fn new(dir: &Path, slug: &str) -> Self {
+        BuildLog {
+            path: dir.join(format!("{}.log", slug)),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn push(&mut self, line: &str) {
+        self.lines.push(strip_ansi(line));
+    }
+
+    /// Overwrites the last line, as a `replace` message does on the terminal
+    pub fn replace(&mut self, line: &str) {
+        self.lines.pop();
+        self.push(line);
+    }
+
+    pub fn erase(&mut self) {
+        self.lines.pop();
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Creating log directory {:?} failed", parent))?;
+        }
+
+        let mut contents = self.lines.join("\n");
+        contents.push('\n');
+
+        std::fs::write(&self.path, contents)
+            .context(format!("Writing build log {:?} failed", self.path))?;
+
+        info!("Build log saved to {:?}", self.path);
+
+        Ok(())
+    }
+}
+
+impl BuildEventSink for BuildLog {
+    fn event(&mut self, event: &BuildEvent) -> Result<()> {
+        match event {
+            BuildEvent::Replace(message) => self.replace(message),
+            BuildEvent::Erase => self.erase(),
+            _ => {
+                if let Some(text) = event.text() {
+                    self.push(&text);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_dots_in_slug() {
+        let build_log = BuildLog::new(Path::new("logs"), "rpi.v2");
+
+        assert_eq!(build_log.path(), Path::new("logs/rpi.v2.log"));
+    }
+}
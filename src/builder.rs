@@ -1,166 +1,322 @@
 use std::io::{stdout, Write};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Context, Result};
-use log::info;
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
 
 use crossterm::cursor::MoveUp;
 use crossterm::execute;
 use crossterm::style::Print;
 use crossterm::terminal::{Clear, ClearType};
 
-use serde_json::{Deserializer, Value};
+use serde::Deserialize;
 
+use crate::api::{BalenaClient, Query};
 use crate::application::Application;
-use crate::application::User;
+use crate::build_event::{BuildEvent, BuildEventSink, BuildEventStream};
+use crate::build_log::BuildLog;
+use crate::release::{wait_for_release, Release};
+use crate::terminal::{strip_ansi, Terminal};
 
-const BUILDER_BASE: &str = "https://builder.balena-cloud.com";
 const BUILD_ENDPOINT: &str = "v3/build";
 
+// Reported by the builder when no machine of the target architecture is available
+const NO_NATIVE_BUILDER: &str = "no native builder";
+
+// Minimum time between replaced progress lines printed without cursor movements
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Remote builder options, set per target
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BuildOptions {
+    pub nocache: bool,
+    pub emulated: bool,
+    pub dockerfile_path: Option<String>,
+    pub headless: bool,
+}
+
+/// Builder reply to a headless build, which runs on without an open connection
+#[derive(Debug, Deserialize)]
+struct HeadlessBuild {
+    started: bool,
+    #[serde(rename = "releaseId")]
+    release_id: Option<u64>,
+    message: Option<String>,
+}
+
+#[derive(Debug)]
+enum BuildOutcome {
+    /// With the finished release when the build was followed headless
+    Success(Option<Release>),
+    Failure,
+    NoNativeBuilder,
+}
+
+/// Builds remotely, recording the events in the build log and passing them on to `events`
 pub async fn build_application(
-    token: &str,
+    client: &BalenaClient,
     application: &Application,
-    user: &User,
+    owner: &str,
     gzip: Vec<u8>,
-) -> Result<()> {
-    info!("Invoking remote build for '{}'", application.name);
-
-    let endpoint = get_build_application_endpoint(&user.username, &application.name);
-    let url = format!("{}/{}", BUILDER_BASE, endpoint);
-    let response = reqwest::Client::new()
-        .post(&url)
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
-        .header(reqwest::header::CONTENT_ENCODING, "gzip")
-        .body(gzip)
-        .send()
-        .await?;
-
-    let success = parse_build_stream(response)
-        .await
-        .context("Processing build stream failed")?;
-
-    if success {
-        info!("Remote build for '{}' succeeded", application.name);
-        Ok(())
-    } else {
-        Err(anyhow!("Remote build for '{}' failed", application.name))
+    options: &BuildOptions,
+    build_log: &mut BuildLog,
+    events: &mut dyn BuildEventSink,
+) -> Result<Option<Release>> {
+    let mut sinks = (&mut *build_log, events);
+
+    let outcome = invoke_build(client, application, owner, gzip, options, &mut sinks).await;
+
+    // A log that cannot be saved must not hide the outcome of the build itself
+    let failed = match build_log.save() {
+        Ok(()) => format!(
+            "Remote build for '{}' failed, see {:?}",
+            application.name,
+            build_log.path()
+        ),
+        Err(err) => {
+            warn!("{:#}", err);
+            format!("Remote build for '{}' failed", application.name)
+        }
+    };
+
+    match outcome {
+        Ok(BuildOutcome::Success(release)) => {
+            info!("Remote build for '{}' succeeded", application.name);
+            Ok(release)
+        }
+        Ok(_) => bail!(failed),
+        Err(error) => Err(error.context(failed)),
     }
 }
 
-async fn parse_build_stream(mut response: reqwest::Response) -> Result<bool> {
-    let mut stream = ArrayStream::new();
+async fn invoke_build(
+    client: &BalenaClient,
+    application: &Application,
+    owner: &str,
+    gzip: Vec<u8>,
+    options: &BuildOptions,
+    events: &mut dyn BuildEventSink,
+) -> Result<BuildOutcome> {
+    let mut options = options.clone();
 
-    let mut success = false;
+    loop {
+        info!("Invoking remote build for '{}'", application.name);
 
-    while let Some(chunk) = response.chunk().await? {
-        stream.extend(std::str::from_utf8(&chunk).context("Response is not an utf-8 string")?);
-        for value in &mut stream {
-            let obj = value
-                .as_object()
-                .context("Serialized response is not an object")?;
-
-            if let Some(is_success) = obj.get("isSuccess") {
-                let is_success = is_success
-                    .as_bool()
-                    .context("Message isSuccess property is not a boolean")?;
-                success = is_success;
-            }
+        let endpoint = get_build_application_endpoint(owner, &application.name, &options);
+        let response = client
+            .post_build(&endpoint, gzip.clone())
+            .await
+            .context("Invoking remote build failed")?;
 
-            if let Some(message) = obj.get("message") {
-                if let Some(replace) = obj.get("replace") {
-                    let replace = replace
-                        .as_bool()
-                        .context("Message replace property is not a boolean")?;
-                    if replace {
-                        execute!(stdout(), MoveUp(1))?;
-                    }
-                }
-                let message = message
-                    .as_str()
-                    .context("Response message is not a string")?;
-                execute!(stdout(), Print(message), Print('\n'))?;
+        let outcome = if options.headless {
+            follow_headless_build(client, response, events)
+                .await
+                .context("Following headless build failed")?
+        } else {
+            parse_build_stream(response, client.read_timeout(), events)
+                .await
+                .context("Processing build stream failed")?
+        };
+
+        events.finish()?;
+
+        match outcome {
+            BuildOutcome::NoNativeBuilder if !options.emulated => {
+                warn!(
+                    "No native builder for '{}', retrying with emulation",
+                    application.name
+                );
+                options.emulated = true;
             }
+            _ => return Ok(outcome),
+        }
+    }
+}
+
+async fn follow_headless_build(
+    client: &BalenaClient,
+    response: reqwest::Response,
+    events: &mut dyn BuildEventSink,
+) -> Result<BuildOutcome> {
+    let build = response.json::<HeadlessBuild>().await?;
+
+    let message = build.message.unwrap_or_default();
+
+    let release_id = match build.release_id {
+        Some(release_id) if build.started => release_id,
+        _ if is_no_native_builder(&message) => return Ok(BuildOutcome::NoNativeBuilder),
+        _ => bail!("Headless build was not started: {}", message),
+    };
+
+    info!("Headless build started for release {}", release_id);
+
+    let release = wait_for_release(client, release_id).await?;
+
+    for (service_name, service_log) in release.build_logs() {
+        events.event(&BuildEvent::Message(format!("[{}]", service_name)))?;
+
+        for line in service_log.lines() {
+            events.event(&BuildEvent::Message(line.to_string()))?;
+        }
+    }
+
+    let success = release.is_success();
+    events.event(&BuildEvent::Finished {
+        success,
+        message: None,
+    })?;
+
+    Ok(if success {
+        BuildOutcome::Success(Some(release))
+    } else {
+        BuildOutcome::Failure
+    })
+}
+
+fn is_no_native_builder(message: &str) -> bool {
+    message.to_lowercase().contains(NO_NATIVE_BUILDER)
+}
 
-            if let Some(resource) = obj.get("resource") {
-                let resource = resource
-                    .as_str()
-                    .context("Resource property is not a string")?;
-
-                if resource == "cursor" {
-                    let value = obj
-                        .get("value")
-                        .context("No replace property defined")?
-                        .as_str()
-                        .context("Value is not a string")?;
-                    if value == "erase" {
-                        execute!(stdout(), MoveUp(1), Clear(ClearType::CurrentLine))?;
-                    }
-                }
+async fn parse_build_stream(
+    response: reqwest::Response,
+    read_timeout: Duration,
+    events: &mut dyn BuildEventSink,
+) -> Result<BuildOutcome> {
+    let mut stream = BuildEventStream::new(response, read_timeout);
+
+    let mut success = false;
+    let mut no_native_builder = false;
+
+    while let Some(event) = stream.next().await? {
+        match event {
+            BuildEvent::Message(ref message) | BuildEvent::Replace(ref message)
+                if is_no_native_builder(message) =>
+            {
+                no_native_builder = true;
             }
+            BuildEvent::Finished {
+                success: is_success,
+                ..
+            } => success = is_success,
+            BuildEvent::Unknown(ref value) => debug!("Unknown build event {}", value),
+            _ => {}
         }
+
+        events.event(&event)?;
     }
 
-    Ok(success)
+    Ok(if success {
+        BuildOutcome::Success(None)
+    } else if no_native_builder {
+        BuildOutcome::NoNativeBuilder
+    } else {
+        BuildOutcome::Failure
+    })
 }
 
-pub struct ArrayStream {
-    buffer: String,
-    started: bool,
+/// Renders build events on the terminal
+///
+/// Without an interactive terminal replaced lines are printed as plain lines, at most one
+/// per `PROGRESS_INTERVAL`, with the latest one always printed before the next regular line.
+pub struct TerminalOutput {
+    terminal: Terminal,
+    last_progress: Option<Instant>,
+    pending: Option<String>,
 }
 
-impl ArrayStream {
-    pub fn new() -> Self {
-        ArrayStream {
-            buffer: String::new(),
-            started: false,
+impl BuildEventSink for TerminalOutput {
+    fn event(&mut self, event: &BuildEvent) -> Result<()> {
+        match event {
+            BuildEvent::Replace(message) => self.replace(message),
+            BuildEvent::Erase => self.erase(),
+            _ => match event.text() {
+                Some(text) => self.line(&text),
+                None => Ok(()),
+            },
         }
     }
 
-    pub fn extend(&mut self, other: &str) {
-        self.buffer.push_str(other);
+    fn finish(&mut self) -> Result<()> {
+        self.flush_pending()
     }
+}
 
-    fn next_value(&mut self) -> (Option<Value>, usize) {
-        let mut cut: usize = 0;
-
-        for (i, ch) in self.buffer.chars().enumerate() {
-            match ch {
-                '\n' => continue,
-                '[' if !self.started => {
-                    self.started = true;
-                    continue;
-                }
-                ',' if self.started => continue,
-                _ => {
-                    cut = i;
-                    break;
-                }
-            }
+impl TerminalOutput {
+    pub fn new(terminal: Terminal) -> Self {
+        TerminalOutput {
+            terminal,
+            last_progress: None,
+            pending: None,
         }
+    }
+
+    fn line(&mut self, message: &str) -> Result<()> {
+        self.flush_pending()?;
+        self.last_progress = None;
+
+        self.print(message)
+    }
 
-        let substring = &self.buffer[cut..];
+    fn replace(&mut self, message: &str) -> Result<()> {
+        if self.terminal.interactive {
+            execute!(stdout(), MoveUp(1))?;
+            return self.print(message);
+        }
 
-        let mut stream = Deserializer::from_str(substring).into_iter::<Value>();
+        let due = match self.last_progress {
+            Some(last_progress) => last_progress.elapsed() >= PROGRESS_INTERVAL,
+            None => true,
+        };
 
-        match stream.next() {
-            Some(result) => (result.ok(), cut + stream.byte_offset()),
-            None => (None, cut),
+        if due {
+            self.last_progress = Some(Instant::now());
+            self.pending = None;
+            self.print(message)
+        } else {
+            self.pending = Some(message.to_string());
+            Ok(())
         }
     }
-}
 
-impl Iterator for ArrayStream {
-    type Item = Value;
+    fn erase(&mut self) -> Result<()> {
+        if self.terminal.interactive {
+            execute!(stdout(), MoveUp(1), Clear(ClearType::CurrentLine))?;
+        } else {
+            self.pending = None;
+        }
+
+        Ok(())
+    }
+
+    fn flush_pending(&mut self) -> Result<()> {
+        match self.pending.take() {
+            Some(message) => self.print(&message),
+            None => Ok(()),
+        }
+    }
+
+    fn print(&self, message: &str) -> Result<()> {
+        if self.terminal.color {
+            execute!(stdout(), Print(message), Print('\n'))?;
+        } else {
+            execute!(stdout(), Print(strip_ansi(message)), Print('\n'))?;
+        }
 
-    fn next(&mut self) -> Option<Value> {
-        let (value_option, byte_offset) = self.next_value();
-        self.buffer.drain(..byte_offset);
-        value_option
+        Ok(())
     }
 }
 
-fn get_build_application_endpoint(username: &str, app: &str) -> String {
-    format!(
-        "{}?owner={}&app={}&dockerfilePath=&emulated=false&nocache=false&headless=false",
-        BUILD_ENDPOINT, username, app
-    )
+fn get_build_application_endpoint(owner: &str, app: &str, options: &BuildOptions) -> String {
+    Query::new(BUILD_ENDPOINT)
+        .param("owner", owner)
+        .param("app", app)
+        .param(
+            "dockerfilePath",
+            options.dockerfile_path.as_deref().unwrap_or_default(),
+        )
+        .param("emulated", options.emulated)
+        .param("nocache", options.nocache)
+        .param("headless", options.headless)
+        .endpoint()
 }
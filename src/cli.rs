@@ -1,8 +1,35 @@
 use clap::{Arg, ArgMatches};
 
+use crate::terminal::ColorMode;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Command {
+    #[default]
+    Build,
+    List,
+    Clean,
+}
+
+#[derive(Debug, Default)]
 pub struct CliArgs {
+    pub command: Command,
     pub config: String,
     pub token: String,
+    pub retries: Option<u32>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub device_state_timeout: Option<u64>,
+    pub ca_cert: Option<String>,
+    pub api_url: Option<String>,
+    pub builder_url: Option<String>,
+    pub insecure_registry: bool,
+    pub recreate_mismatched: bool,
+    pub no_cache: bool,
+    pub emulated: bool,
+    pub color: ColorMode,
+    pub registry_login: Option<String>,
+    pub credentials_dir: Option<String>,
+    pub credentials_passphrase: Option<String>,
 }
 
 pub fn read_cli_args() -> CliArgs {
@@ -29,12 +56,187 @@ pub fn read_cli_args() -> CliArgs {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("RETRIES")
+                .long("retries")
+                .value_name("retries")
+                .env("CROSSER_RETRIES")
+                .help("Maximum retries of transient API and download failures")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CONNECT_TIMEOUT")
+                .long("connect-timeout")
+                .value_name("seconds")
+                .env("CROSSER_CONNECT_TIMEOUT")
+                .help("Connect timeout for API and builder requests")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("READ_TIMEOUT")
+                .long("read-timeout")
+                .value_name("seconds")
+                .env("CROSSER_READ_TIMEOUT")
+                .help("Read timeout for API requests and the build stream")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("DEVICE_STATE_TIMEOUT")
+                .long("device-state-timeout")
+                .value_name("seconds")
+                .env("CROSSER_DEVICE_STATE_TIMEOUT")
+                .help("Timeout for the device state to reflect a new release")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CA_CERT")
+                .long("ca-cert")
+                .value_name("ca-cert")
+                .env("CROSSER_CA_CERT")
+                .help("Additional trusted CA certificate in PEM format")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("API_URL")
+                .long("api-url")
+                .value_name("api-url")
+                .env("CROSSER_API_URL")
+                .help("Balena API URL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("BUILDER_URL")
+                .long("builder-url")
+                .value_name("builder-url")
+                .env("CROSSER_BUILDER_URL")
+                .help("Balena builder URL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("INSECURE_REGISTRY")
+                .long("insecure-registry")
+                .help("Access the image registry over plain HTTP"),
+        )
+        .arg(
+            Arg::with_name("RECREATE_MISMATCHED")
+                .long("recreate-mismatched")
+                .help("Delete and recreate applications with a mismatching device type"),
+        )
+        .arg(
+            Arg::with_name("NO_CACHE")
+                .long("no-cache")
+                .help("Build without the builder image cache"),
+        )
+        .arg(
+            Arg::with_name("EMULATED")
+                .long("emulated")
+                .help("Build with emulation instead of a native builder"),
+        )
+        .arg(
+            Arg::with_name("COLOR")
+                .long("color")
+                .value_name("when")
+                .help("Use colors and redraw progress lines")
+                .possible_values(&["auto", "always", "never"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("REGISTRY_LOGIN")
+                .long("registry-login")
+                .value_name("login")
+                .env("CROSSER_REGISTRY_LOGIN")
+                .help("Registry credentials for pulling images")
+                .possible_values(&["device", "user"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CREDENTIALS_DIR")
+                .long("credentials-dir")
+                .value_name("dir")
+                .env("CROSSER_CREDENTIALS_DIR")
+                .help("Directory of the local device credentials store")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CREDENTIALS_PASSPHRASE")
+                .long("credentials-passphrase")
+                .value_name("passphrase")
+                .env("CROSSER_CREDENTIALS_PASSPHRASE")
+                .hide_env_values(true)
+                .help("Passphrase for encrypting the local device credentials store")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("LIST")
+                .long("list")
+                .help("List applications managed for the config file and exit"),
+        )
+        .arg(
+            Arg::with_name("CLEAN")
+                .long("clean")
+                .conflicts_with("LIST")
+                .help("Delete applications managed for the config file and exit"),
+        )
         .get_matches();
 
+    let command = if matches.is_present("LIST") {
+        Command::List
+    } else if matches.is_present("CLEAN") {
+        Command::Clean
+    } else {
+        Command::Build
+    };
+
     let config = get_existing_arg(&matches, "CONFIG");
     let token = get_existing_arg(&matches, "TOKEN");
+    let retries = get_optional_parsed_arg(&matches, "RETRIES");
+    let connect_timeout = get_optional_parsed_arg(&matches, "CONNECT_TIMEOUT");
+    let read_timeout = get_optional_parsed_arg(&matches, "READ_TIMEOUT");
+    let device_state_timeout = get_optional_parsed_arg(&matches, "DEVICE_STATE_TIMEOUT");
+    let ca_cert = matches
+        .value_of("CA_CERT")
+        .map(|ca_cert| ca_cert.to_string());
+    let api_url = matches
+        .value_of("API_URL")
+        .map(|api_url| api_url.to_string());
+    let builder_url = matches
+        .value_of("BUILDER_URL")
+        .map(|builder_url| builder_url.to_string());
+    let insecure_registry = matches.is_present("INSECURE_REGISTRY");
+    let recreate_mismatched = matches.is_present("RECREATE_MISMATCHED");
+    let no_cache = matches.is_present("NO_CACHE");
+    let emulated = matches.is_present("EMULATED");
+    let color = get_optional_parsed_arg(&matches, "COLOR").unwrap_or_default();
+    let registry_login = matches
+        .value_of("REGISTRY_LOGIN")
+        .map(|registry_login| registry_login.to_string());
+    let credentials_dir = matches
+        .value_of("CREDENTIALS_DIR")
+        .map(|credentials_dir| credentials_dir.to_string());
+    let credentials_passphrase = matches
+        .value_of("CREDENTIALS_PASSPHRASE")
+        .map(|credentials_passphrase| credentials_passphrase.to_string());
 
-    CliArgs { config, token }
+    CliArgs {
+        command,
+        config,
+        token,
+        retries,
+        connect_timeout,
+        read_timeout,
+        device_state_timeout,
+        ca_cert,
+        api_url,
+        builder_url,
+        insecure_registry,
+        recreate_mismatched,
+        no_cache,
+        emulated,
+        color,
+        registry_login,
+        credentials_dir,
+        credentials_passphrase,
+    }
 }
 
 fn get_existing_arg(matches: &ArgMatches, name: &str) -> String {
@@ -44,3 +246,11 @@ fn get_existing_arg(matches: &ArgMatches, name: &str) -> String {
         unreachable!()
     }
 }
+
+fn get_optional_parsed_arg<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    if matches.is_present(name) {
+        Some(value_t_or_exit!(matches, name, T))
+    } else {
+        None
+    }
+}
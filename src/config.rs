@@ -3,36 +3,130 @@ use std::fmt::Debug;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 use serde_yaml::from_reader;
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+
+use crate::api::{ApiVersion, Timeouts, Tls};
+use crate::builder::BuildOptions;
+use crate::registry::RegistryLogin;
+use crate::retry::RetryPolicy;
+
+const DEFAULT_LOGS_DIR: &str = "logs";
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    pub project: Option<String>,
+    pub application_name: Option<String>,
+    pub organization: Option<String>,
     pub source: String,
-    pub copy: CopySpec,
+    #[serde(deserialize_with = "one_or_many")]
+    pub copy: Vec<CopySpec>,
     pub targets: Vec<Target>,
+    /// Directory for per-target build logs, relative to the config file
+    pub logs_dir: Option<String>,
+    #[serde(default)]
+    pub api_version: ApiVersion,
+    #[serde(default)]
+    pub registry_login: RegistryLogin,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Items per request when listing devices and tags
+    pub page_size: Option<u64>,
+    #[serde(default)]
+    pub tls: Tls,
+}
+
+impl Config {
+    pub fn logs_dir(&self, config_dir: &Path) -> PathBuf {
+        config_dir.join(self.logs_dir.as_deref().unwrap_or(DEFAULT_LOGS_DIR))
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CopySpec {
     pub from_image: Vec<String>,
     pub to: String,
+    /// Service image to copy from, defaults to the target service
+    pub service: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Target {
     pub slug: String,
     pub device_type: String,
-    pub dockerfile: String,
+    pub dockerfile: Option<String>,
+    pub compose: Option<String>,
+    pub service: Option<String>,
+    #[serde(default)]
+    pub build: BuildOptions,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BuildFile<'a> {
+    Dockerfile(&'a str),
+    Compose(&'a str),
+}
+
+impl<'a> BuildFile<'a> {
+    pub fn path(&self) -> &'a str {
+        match *self {
+            BuildFile::Dockerfile(path) | BuildFile::Compose(path) => path,
+        }
+    }
+}
+
+impl Target {
+    pub fn build_file(&self) -> Result<BuildFile<'_>> {
+        match (&self.dockerfile, &self.compose) {
+            (Some(dockerfile), None) => Ok(BuildFile::Dockerfile(dockerfile)),
+            (None, Some(compose)) => Ok(BuildFile::Compose(compose)),
+            (Some(_), Some(_)) => bail!(
+                "Target '{}' sets both 'dockerfile' and 'compose'",
+                self.slug
+            ),
+            (None, None) => bail!(
+                "Target '{}' needs either 'dockerfile' or 'compose'",
+                self.slug
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(item) => vec![item],
+        OneOrMany::Many(items) => items,
+    })
 }
 
 pub fn read_config(path: &str) -> Result<Config> {
     let file = File::open(path).context(format!("Opening config file '{}' failed", path))?;
 
-    Ok(from_reader(file).context(format!("Deserializing config file '{}' failed", path))?)
+    let config: Config =
+        from_reader(file).context(format!("Deserializing config file '{}' failed", path))?;
+
+    for target in &config.targets {
+        target.build_file()?;
+    }
+
+    Ok(config)
 }
 
 pub fn config_dir<P>(config_path: P) -> Result<PathBuf>
@@ -45,6 +139,20 @@ where
     Ok(absolute)
 }
 
+pub fn config_hash<P>(config_path: P) -> Result<String>
+where
+    P: AsRef<Path> + Debug,
+{
+    let canonical = config_path
+        .as_ref()
+        .canonicalize()
+        .context(format!("Cannot resolve config path {:?}", config_path))?;
+
+    let digest = Sha256::digest(canonical.to_string_lossy().as_bytes());
+
+    Ok(hex::encode(&digest[..8]))
+}
+
 pub fn config_name<P>(config_path: P) -> Result<String>
 where
     P: AsRef<Path> + Debug,
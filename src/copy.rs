@@ -11,17 +11,19 @@ use fs_extra::dir::CopyOptions;
 use glob::glob;
 use ignore::Walk;
 
-use crate::config::{Config, Target};
+use crate::config::{BuildFile, Config, CopySpec, Target};
 
-pub fn copy_from_image(config: &Config, slug: &str, temp_dir: TempDir) -> Result<()> {
-    let relative = Path::new(&config.copy.to).join(&slug);
+const COMPOSE_FILE: &str = "docker-compose.yml";
+
+pub fn copy_from_image(copy: &CopySpec, slug: &str, image_dir: &Path) -> Result<()> {
+    let relative = Path::new(&copy.to).join(slug);
     std::fs::create_dir_all(&relative).context("Failed to create destination directory")?;
 
     let mut entries = Vec::new();
 
-    let temp_dir_str = temp_dir.path().to_string_lossy();
+    let temp_dir_str = image_dir.to_string_lossy();
 
-    for src_glob in &config.copy.from_image {
+    for src_glob in &copy.from_image {
         let abs_glob = format!("{}{}", temp_dir_str, src_glob);
         for glob_result in
             glob(&abs_glob).context(format!("Failed to read glob pattern {}", abs_glob))?
@@ -48,14 +50,20 @@ where
     let temp_dir =
         TempDir::new().context("Creating temp directory for assembling sources failed")?;
 
-    let dockerfile_from = config_dir.as_ref().join(&target.dockerfile);
-    let dockerfile_to = temp_dir.path().join(
-        dockerfile_from
-            .file_name()
-            .context("Failed to get Dockerfile file name")?,
-    );
-
-    std::fs::copy(dockerfile_from, dockerfile_to).context("Failed to copy Dockerfile")?;
+    // A compose file always lands at the root under the name the builder looks for
+    let build_file = target.build_file()?;
+    let build_file_from = config_dir.as_ref().join(build_file.path());
+    let build_file_to = match build_file {
+        BuildFile::Dockerfile(_) => temp_dir.path().join(
+            build_file_from
+                .file_name()
+                .context("Failed to get Dockerfile file name")?,
+        ),
+        BuildFile::Compose(_) => temp_dir.path().join(COMPOSE_FILE),
+    };
+
+    std::fs::copy(&build_file_from, build_file_to)
+        .context(format!("Failed to copy {:?}", build_file_from))?;
 
     std::env::set_current_dir(config_dir.as_ref().join(&config.source))?;
 
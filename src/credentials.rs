@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+const CREDENTIALS_FILE: &str = "credentials.json";
+
+const KEY_DERIVATION_ROUNDS: usize = 100_000;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+
+type DeviceKeys = BTreeMap<String, String>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+enum CredentialsFile {
+    Plain {
+        devices: DeviceKeys,
+    },
+    Encrypted {
+        salt: String,
+        nonce: String,
+        ciphertext: String,
+    },
+}
+
+/// Device API keys stored locally, keyed by device UUID
+pub struct CredentialsStore {
+    path: PathBuf,
+    passphrase: Option<String>,
+    devices: DeviceKeys,
+}
+
+impl CredentialsStore {
+    pub fn open(dir: Option<&Path>, passphrase: Option<&str>) -> Result<Self> {
+        let path = match dir {
+            Some(dir) => dir.to_path_buf(),
+            None => default_credentials_dir()?,
+        }
+        .join(CREDENTIALS_FILE);
+
+        let passphrase = passphrase.map(|passphrase| passphrase.to_string());
+
+        let devices = if path.exists() {
+            read_credentials_file(&path, passphrase.as_deref())?
+        } else {
+            DeviceKeys::new()
+        };
+
+        Ok(CredentialsStore {
+            path,
+            passphrase,
+            devices,
+        })
+    }
+
+    pub fn device_api_key(&self, uuid: &str) -> Option<&str> {
+        self.devices.get(uuid).map(String::as_str)
+    }
+
+    pub fn store_device_api_key(&mut self, uuid: &str, api_key: &str) -> Result<()> {
+        self.devices.insert(uuid.to_string(), api_key.to_string());
+        self.save()?;
+
+        info!("Stored '{}' API key in {:?}", uuid, self.path);
+
+        Ok(())
+    }
+
+    pub fn remove_device_api_key(&mut self, uuid: &str) -> Result<()> {
+        if self.devices.remove(uuid).is_some() {
+            self.save()?;
+        }
+
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = match self.passphrase {
+            Some(ref passphrase) => encrypt(&self.devices, passphrase)?,
+            None => CredentialsFile::Plain {
+                devices: self.devices.clone(),
+            },
+        };
+
+        let contents = serde_json::to_vec_pretty(&file)?;
+
+        write_private_file(&self.path, &contents)
+            .context(format!("Writing credentials file {:?} failed", self.path))
+    }
+}
+
+fn default_credentials_dir() -> Result<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) if !config_home.is_empty() => PathBuf::from(config_home),
+        _ => {
+            let home = std::env::var_os("HOME").context("Neither XDG_CONFIG_HOME nor HOME set")?;
+            PathBuf::from(home).join(".config")
+        }
+    };
+
+    Ok(config_home.join(env!("CARGO_PKG_NAME")))
+}
+
+fn read_credentials_file(path: &Path, passphrase: Option<&str>) -> Result<DeviceKeys> {
+    let contents = fs::read(path).context(format!("Reading credentials file {:?} failed", path))?;
+
+    let file = serde_json::from_slice::<CredentialsFile>(&contents)
+        .context(format!("Deserializing credentials file {:?} failed", path))?;
+
+    match (file, passphrase) {
+        (CredentialsFile::Plain { devices }, _) => Ok(devices),
+        (encrypted, Some(passphrase)) => decrypt(encrypted, passphrase)
+            .context(format!("Decrypting credentials file {:?} failed", path)),
+        (_, None) => bail!(
+            "Credentials file {:?} is encrypted, a passphrase is required",
+            path
+        ),
+    }
+}
+
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp_path = path.with_extension("tmp");
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Key {
+    let mut key = Key::default();
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, KEY_DERIVATION_ROUNDS, &mut key);
+    key
+}
+
+fn encrypt(devices: &DeviceKeys, passphrase: &str) -> Result<CredentialsFile> {
+    let mut salt = [0; SALT_LENGTH];
+    let mut nonce = [0; NONCE_LENGTH];
+    getrandom::getrandom(&mut salt).context("Random generation failed")?;
+    getrandom::getrandom(&mut nonce).context("Random generation failed")?;
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt));
+
+    let plaintext = serde_json::to_vec(devices)?;
+    let ciphertext = cipher
+        .encrypt(&Nonce::from(nonce), plaintext.as_slice())
+        .map_err(|_| anyhow!("Encrypting credentials failed"))?;
+
+    Ok(CredentialsFile::Encrypted {
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+fn decrypt(file: CredentialsFile, passphrase: &str) -> Result<DeviceKeys> {
+    let (salt, nonce, ciphertext) = match file {
+        CredentialsFile::Encrypted {
+            salt,
+            nonce,
+            ciphertext,
+        } => (
+            hex::decode(salt)?,
+            hex::decode(nonce)?,
+            hex::decode(ciphertext)?,
+        ),
+        CredentialsFile::Plain { devices } => return Ok(devices),
+    };
+
+    let nonce: [u8; NONCE_LENGTH] = nonce
+        .as_slice()
+        .try_into()
+        .context(format!("Invalid nonce length {}", nonce.len()))?;
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt));
+
+    let plaintext = cipher
+        .decrypt(&Nonce::from(nonce), ciphertext.as_slice())
+        .map_err(|_| anyhow!("Wrong passphrase or corrupted credentials"))?;
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
@@ -1,15 +1,22 @@
-use anyhow::{Context, Result};
-use log::info;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
 
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::json;
 
-use crate::api::{get, patch, post, Response};
-use crate::application::{Application, User};
-use crate::variable::{get_device_api_key, store_device_api_key};
+use crate::api::{BalenaClient, Filter, Order, Query};
+use crate::application::Application;
+use crate::credentials::CredentialsStore;
+use crate::image::{select_service_image, ServiceImage};
+use crate::release::Release;
+use crate::user::User;
+use crate::variable::{delete_legacy_device_api_key, get_legacy_device_api_key};
 
 const REGISTER_ENDPOINT: &str = "device/register";
-const DEVICE_ENDPOINT: &str = "v5/device";
+const RESOURCE_DEVICE: &str = "device";
 
 #[derive(Debug, Serialize)]
 pub struct DeviceRegistrationRequest {
@@ -32,7 +39,6 @@ pub struct DeviceRegistration {
 pub struct Device {
     pub id: u64,
     pub uuid: String,
-    pub device_type: String,
     #[serde(rename = "device_name")]
     pub name: String,
 }
@@ -43,24 +49,25 @@ pub struct DeviceNameData {
 }
 
 pub async fn create_device(
-    token: &str,
+    client: &BalenaClient,
+    credentials: &mut CredentialsStore,
     application: &Application,
     user: &User,
     name: &str,
 ) -> Result<DeviceRegistration> {
     info!("Creating device '{}'", name);
 
-    let registration = register_device(token, application, user).await?;
+    let registration = register_device(client, application, user).await?;
 
-    rename_device(token, &registration, name).await?;
+    rename_device(client, &registration, name).await?;
 
-    store_device_api_key(token, registration.id, &registration.api_key).await?;
+    credentials.store_device_api_key(&registration.uuid, &registration.api_key)?;
 
     Ok(registration)
 }
 
 pub async fn register_device(
-    token: &str,
+    client: &BalenaClient,
     application: &Application,
     user: &User,
 ) -> Result<DeviceRegistration> {
@@ -71,8 +78,10 @@ pub async fn register_device(
         uuid: new_uuid()?,
     };
 
-    let registration = post(token, REGISTER_ENDPOINT, &input)
-        .await?
+    let registration = client
+        .post(REGISTER_ENDPOINT, &input)
+        .await
+        .context("Registering device failed")?
         .json::<DeviceRegistration>()
         .await?;
 
@@ -85,7 +94,7 @@ pub async fn register_device(
 }
 
 pub async fn rename_device(
-    token: &str,
+    client: &BalenaClient,
     registration: &DeviceRegistration,
     name: &str,
 ) -> Result<()> {
@@ -93,13 +102,16 @@ pub async fn rename_device(
         device_name: name.to_string(),
     };
 
-    let _result = patch(token, &get_device_id_endpoint(registration.id), &name_data).await?;
+    client
+        .patch(&get_device_id_endpoint(client, registration.id), &name_data)
+        .await
+        .context(format!("Renaming device '{}' failed", registration.uuid))?;
 
     Ok(())
 }
 
-fn get_device_id_endpoint(device_id: u64) -> String {
-    format!("{}({})", DEVICE_ENDPOINT, device_id)
+fn get_device_id_endpoint(client: &BalenaClient, device_id: u64) -> String {
+    format!("{}({})", client.resource(RESOURCE_DEVICE), device_id)
 }
 
 fn new_uuid() -> Result<String> {
@@ -108,78 +120,250 @@ fn new_uuid() -> Result<String> {
     Ok(hex::encode(buf))
 }
 
-pub async fn get_device_image_url(token: &str, uuid: &str) -> Result<String> {
-    info!("Getting image URL from '{}' device state", uuid);
+#[derive(Debug, Deserialize)]
+pub struct DeviceState {
+    pub local: LocalState,
+}
 
-    let value = get(token, &get_device_state_endpoint(uuid))
-        .await?
-        .json::<Value>()
-        .await?;
+#[derive(Debug, Deserialize)]
+pub struct LocalState {
+    #[serde(default)]
+    pub apps: BTreeMap<String, AppState>,
+}
 
-    let image_url =
-        get_image_from_device_state(&value).context("Image not found in device state")?;
+#[derive(Debug, Deserialize)]
+pub struct AppState {
+    #[serde(rename = "releaseId")]
+    pub release_id: Option<u64>,
+    pub commit: Option<String>,
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceState>,
+}
 
-    Ok(image_url)
+#[derive(Debug, Deserialize)]
+pub struct ServiceState {
+    #[serde(rename = "serviceName")]
+    pub service_name: String,
+    pub image: String,
 }
 
-fn get_device_state_endpoint(uuid: &str) -> String {
-    format!("device/v2/{}/state", uuid)
+impl AppState {
+    fn is_release(&self, release: &Release) -> bool {
+        self.release_id == Some(release.id) || self.commit.as_ref() == Some(&release.commit)
+    }
+
+    fn service_images(&self) -> Vec<ServiceImage> {
+        self.services
+            .values()
+            .map(|service| ServiceImage {
+                service_name: service.service_name.clone(),
+                image_url: service.image.clone(),
+            })
+            .collect()
+    }
+}
+
+impl DeviceState {
+    /// Service images of the release, if the state already targets it
+    pub fn release_images(&self, release: &Release) -> Option<Vec<ServiceImage>> {
+        self.local
+            .apps
+            .values()
+            .find(|app| app.is_release(release))
+            .map(AppState::service_images)
+    }
+}
+
+pub async fn get_device_state(client: &BalenaClient, uuid: &str) -> Result<DeviceState> {
+    client
+        .get_json::<DeviceState>(&get_device_state_endpoint(uuid))
+        .await
+        .context(format!("Getting '{}' device state failed", uuid))
 }
 
-fn get_image_from_device_state(val: &Value) -> Option<String> {
-    if let Some(map) = val.as_object() {
-        for (key, inner) in map.iter() {
-            if key == "image" {
-                return inner.as_str().map(|s| s.to_string());
-            } else {
-                let result = get_image_from_device_state(inner);
-                if result.is_some() {
-                    return result;
-                }
-            }
+/// Polls the device state until it targets the release and returns the service image URL
+pub async fn wait_for_device_release(
+    client: &BalenaClient,
+    uuid: &str,
+    release: &Release,
+    service: Option<&str>,
+) -> Result<String> {
+    info!(
+        "Waiting for '{}' device state to reflect release '{}'",
+        uuid, release.commit
+    );
+
+    let started = Instant::now();
+
+    loop {
+        if let Some(images) = get_device_state(client, uuid)
+            .await?
+            .release_images(release)
+        {
+            let image = select_service_image(&images, service)
+                .context("Image not found in device state")?;
+
+            return Ok(image.image_url.clone());
+        }
+
+        if started.elapsed() >= client.device_state_timeout() {
+            bail!(
+                "Device '{}' state did not reflect release '{}' within {} seconds",
+                uuid,
+                release.commit,
+                client.device_state_timeout().as_secs()
+            );
         }
+
+        tokio::time::delay_for(client.poll_interval()).await;
     }
+}
 
-    None
+fn get_device_state_endpoint(uuid: &str) -> String {
+    format!("device/v2/{}/state", uuid)
 }
 
+/// Finds the device named after the slug with a usable API key, reconciling
+/// same-named devices left behind by earlier runs
 pub async fn get_device_registration(
-    token: &str,
+    client: &BalenaClient,
+    credentials: &mut CredentialsStore,
     application: &Application,
     slug: &str,
 ) -> Result<Option<DeviceRegistration>> {
-    if let Some(device) = get_device_by_name(token, application, slug).await? {
-        if let Some(api_key) = get_device_api_key(token, device.id).await? {
-            return Ok(Some(DeviceRegistration {
-                id: device.id,
-                uuid: device.uuid,
-                api_key,
-            }));
+    let devices = get_devices_by_name(client, application, slug).await?;
+
+    if devices.is_empty() {
+        return Ok(None);
+    }
+
+    let mut keyed = None;
+    for device in &devices {
+        let api_key = match credentials.device_api_key(&device.uuid) {
+            Some(api_key) => Some(api_key.to_string()),
+            None => migrate_legacy_device_api_key(client, credentials, device).await?,
+        };
+
+        if let Some(api_key) = api_key {
+            keyed = Some((device, api_key));
+            break;
         }
     }
 
-    Ok(None)
+    let mut report = Vec::new();
+
+    // Devices are ordered newest first, so the newest one is kept when no key is known
+    let (kept, api_key) = match keyed {
+        Some(keyed) => keyed,
+        None => {
+            let device = &devices[0];
+            let api_key = regenerate_device_api_key(client, device).await?;
+            credentials.store_device_api_key(&device.uuid, &api_key)?;
+            report.push(format!("regenerated API key of '{}'", device.uuid));
+            (device, api_key)
+        }
+    };
+
+    for device in devices.iter().filter(|device| device.id != kept.id) {
+        delete_device(client, device).await?;
+        credentials.remove_device_api_key(&device.uuid)?;
+        report.push(format!("deleted duplicate '{}'", device.uuid));
+    }
+
+    if !report.is_empty() {
+        warn!("Reconciled devices named '{}': {}", slug, report.join(", "));
+    }
+
+    Ok(Some(DeviceRegistration {
+        id: kept.id,
+        uuid: kept.uuid.clone(),
+        api_key,
+    }))
+}
+
+async fn migrate_legacy_device_api_key(
+    client: &BalenaClient,
+    credentials: &mut CredentialsStore,
+    device: &Device,
+) -> Result<Option<String>> {
+    // Store before deleting the variable so the key is never lost
+    let api_key = match get_legacy_device_api_key(client, device.id).await? {
+        Some(api_key) => api_key,
+        None => return Ok(None),
+    };
+
+    credentials.store_device_api_key(&device.uuid, &api_key)?;
+
+    delete_legacy_device_api_key(client, device.id).await?;
+
+    info!(
+        "Migrated '{}' API key from device variable to local credentials",
+        device.uuid
+    );
+
+    Ok(Some(api_key))
+}
+
+fn get_device_key_endpoint(device_id: u64) -> String {
+    format!("api-key/device/{}/device-key", device_id)
+}
+
+async fn regenerate_device_api_key(client: &BalenaClient, device: &Device) -> Result<String> {
+    info!("Regenerating API key of device '{}'", device.uuid);
+
+    Ok(client
+        .post(&get_device_key_endpoint(device.id), &json!({}))
+        .await
+        .context(format!(
+            "Regenerating API key of device '{}' failed",
+            device.uuid
+        ))?
+        .json::<String>()
+        .await?)
 }
 
-fn get_device_by_name_endpoint(application_id: u64, name: &str) -> String {
-    format!(
-        "{}?$filter=belongs_to__application eq '{}' and device_name eq '{}'",
-        DEVICE_ENDPOINT, application_id, name
-    )
+async fn delete_device(client: &BalenaClient, device: &Device) -> Result<()> {
+    info!("Deleting device '{}' ({})", device.uuid, device.id);
+
+    client
+        .delete(&get_device_id_endpoint(client, device.id))
+        .await
+        .context(format!("Deleting device '{}' failed", device.uuid))?;
+
+    Ok(())
 }
 
-pub async fn get_device_by_name(
-    token: &str,
+fn get_devices_by_name_query(client: &BalenaClient, application_id: u64, name: &str) -> Query {
+    Query::new(&client.resource(RESOURCE_DEVICE))
+        .filter(Filter::eq("belongs_to__application", application_id))
+        .filter(Filter::eq("device_name", name))
+        .orderby("id", Order::Desc)
+}
+
+pub async fn get_devices_by_name(
+    client: &BalenaClient,
     application: &Application,
     name: &str,
-) -> Result<Option<Device>> {
-    info!("Getting device by name '{}'", name);
+) -> Result<Vec<Device>> {
+    info!("Getting devices by name '{}'", name);
+
+    client
+        .get_all::<Device>(&get_devices_by_name_query(client, application.id, name))
+        .await
+}
 
-    let mut devices = get(token, &get_device_by_name_endpoint(application.id, name))
-        .await?
-        .json::<Response<Device>>()
-        .await?
-        .data;
+fn get_application_devices_query(client: &BalenaClient, application_id: u64) -> Query {
+    Query::new(&client.resource(RESOURCE_DEVICE))
+        .filter(Filter::eq("belongs_to__application", application_id))
+}
+
+pub async fn get_application_devices(
+    client: &BalenaClient,
+    application: &Application,
+) -> Result<Vec<Device>> {
+    info!("Getting '{}' devices", application.name);
 
-    Ok(devices.pop())
+    client
+        .get_all::<Device>(&get_application_devices_query(client, application.id))
+        .await
 }
@@ -0,0 +1,34 @@
+use anyhow::{bail, Result};
+
+/// Image built for a single service of a release
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceImage {
+    pub service_name: String,
+    pub image_url: String,
+}
+
+pub fn select_service_image<'a>(
+    images: &'a [ServiceImage],
+    service: Option<&str>,
+) -> Result<&'a ServiceImage> {
+    let names = images
+        .iter()
+        .map(|image| image.service_name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match service {
+        Some(service) => match images.iter().find(|image| image.service_name == service) {
+            Some(image) => Ok(image),
+            None => bail!("Service '{}' not found (available: {})", service, names),
+        },
+        None => match images {
+            [] => bail!("No service images found"),
+            [image] => Ok(image),
+            _ => bail!(
+                "Multiple services found ({}), set 'service' in the target to choose one",
+                names
+            ),
+        },
+    }
+}
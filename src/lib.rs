@@ -0,0 +1,358 @@
+#[macro_use]
+extern crate clap;
+
+mod api;
+mod application;
+pub mod build_event;
+mod build_log;
+mod builder;
+pub mod cli;
+mod config;
+mod copy;
+mod credentials;
+mod device;
+mod image;
+pub mod logger;
+mod naming;
+mod organization;
+mod registry;
+mod release;
+mod retry;
+mod tag;
+mod tar;
+pub mod terminal;
+mod user;
+mod variable;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+
+use crate::api::{BalenaClient, ClientSettings};
+use crate::application::{
+    delete_application, get_application_by_id, get_application_user, get_or_create_application,
+    Application,
+};
+use crate::build_event::BuildEventSink;
+use crate::build_log::BuildLog;
+use crate::builder::{build_application, TerminalOutput};
+use crate::cli::{CliArgs, Command};
+use crate::config::{config_dir, config_hash, config_name, read_config, Config};
+use crate::copy::{assemble_sources, copy_from_image};
+use crate::credentials::CredentialsStore;
+use crate::device::{
+    create_device, get_device_registration, wait_for_device_release, DeviceRegistration,
+};
+use crate::image::select_service_image;
+use crate::naming::application_name;
+use crate::organization::get_organization_by_handle;
+use crate::registry::{download_image, RegistryCredentials, RegistryLogin};
+use crate::release::{get_latest_release, Release};
+use crate::tag::get_config_application_ids;
+use crate::tar::tar_gz_dockerfile_directory;
+use crate::terminal::Terminal;
+use crate::user::{get_current_user, User};
+
+pub async fn run(cli_args: CliArgs) -> Result<()> {
+    run_with_build_events(cli_args, &mut ()).await
+}
+
+/// Runs like `run`, also passing the events of every remote build to `events`
+pub async fn run_with_build_events(
+    cli_args: CliArgs,
+    events: &mut dyn BuildEventSink,
+) -> Result<()> {
+    let config_name = config_name(&cli_args.config)?;
+
+    let config = read_config(&cli_args.config)?;
+
+    let config_dir = config_dir(&cli_args.config)?;
+
+    let config_hash = config_hash(&cli_args.config)?;
+
+    let mut settings = ClientSettings {
+        api_version: config.api_version,
+        retry_policy: config.retry.clone(),
+        timeouts: config.timeouts.clone(),
+        insecure_registry: cli_args.insecure_registry,
+        ..Default::default()
+    };
+
+    if let Some(page_size) = config.page_size {
+        settings.page_size = page_size;
+    }
+    if let Some(ref api_url) = cli_args.api_url {
+        settings.api_url = api_url.clone();
+    }
+    if let Some(ref builder_url) = cli_args.builder_url {
+        settings.builder_url = builder_url.clone();
+    }
+    if let Some(retries) = cli_args.retries {
+        settings.retry_policy.max_retries = retries;
+    }
+    if let Some(connect_timeout) = cli_args.connect_timeout {
+        settings.timeouts.connect_secs = connect_timeout;
+    }
+    if let Some(read_timeout) = cli_args.read_timeout {
+        settings.timeouts.read_secs = read_timeout;
+    }
+    if let Some(device_state_timeout) = cli_args.device_state_timeout {
+        settings.timeouts.device_state_secs = device_state_timeout;
+    }
+
+    settings.ca_file = if let Some(ref ca_cert) = cli_args.ca_cert {
+        Some(PathBuf::from(ca_cert))
+    } else {
+        config
+            .tls
+            .ca_file
+            .as_ref()
+            .map(|ca_file| config_dir.join(ca_file))
+    };
+
+    let client = BalenaClient::new(&cli_args.token, settings)?;
+
+    match cli_args.command {
+        Command::Build => {}
+        Command::List => return list_applications(&client, &config_hash).await,
+        Command::Clean => return clean_applications(&client, &config_hash).await,
+    }
+
+    let terminal_output = TerminalOutput::new(Terminal::detect(cli_args.color));
+
+    build_targets(
+        &client,
+        &mut (terminal_output, events),
+        &cli_args,
+        &config,
+        &config_name,
+        &config_dir,
+        &config_hash,
+    )
+    .await
+}
+
+async fn build_targets(
+    client: &BalenaClient,
+    events: &mut dyn BuildEventSink,
+    cli_args: &CliArgs,
+    config: &Config,
+    config_name: &str,
+    config_dir: &Path,
+    config_hash: &str,
+) -> Result<()> {
+    let organization = if let Some(ref handle) = config.organization {
+        Some(get_organization_by_handle(client, handle).await?)
+    } else {
+        None
+    };
+
+    let registry_login = match cli_args.registry_login {
+        Some(ref registry_login) => registry_login.parse()?,
+        None => config.registry_login,
+    };
+
+    let current_user = match registry_login {
+        RegistryLogin::Device => None,
+        RegistryLogin::User => Some(get_current_user(client).await?),
+    };
+
+    let mut credentials = match registry_login {
+        RegistryLogin::Device => {
+            let credentials_dir = match cli_args.credentials_dir {
+                Some(ref credentials_dir) => Some(std::env::current_dir()?.join(credentials_dir)),
+                None => None,
+            };
+            Some(CredentialsStore::open(
+                credentials_dir.as_deref(),
+                cli_args.credentials_passphrase.as_deref(),
+            )?)
+        }
+        RegistryLogin::User => None,
+    };
+
+    for target in &config.targets {
+        let target_source = assemble_sources(config_dir, config, target)?;
+
+        std::env::set_current_dir(config_dir)?;
+
+        info!(
+            "Building '{}' for '{}' from '{}'",
+            target.slug,
+            target.device_type,
+            target.build_file()?.path()
+        );
+
+        let application_name = application_name(config, config_name, target)?;
+
+        let application = get_or_create_application(
+            client,
+            &application_name,
+            &target.device_type,
+            &target.slug,
+            organization.as_ref(),
+            config_hash,
+            cli_args.recreate_mismatched,
+        )
+        .await?;
+
+        let user = get_application_user(client, &application).await?;
+
+        let registration = match credentials {
+            Some(ref mut credentials) => Some(
+                get_or_create_device(client, credentials, &application, &target.slug, &user)
+                    .await?,
+            ),
+            None => None,
+        };
+
+        let gzip = tar_gz_dockerfile_directory(&target_source)?;
+
+        let owner = application.owner().unwrap_or_else(|| {
+            organization
+                .as_ref()
+                .map_or(&user.username, |organization| &organization.handle)
+        });
+
+        let mut build_options = target.build.clone();
+        build_options.nocache |= cli_args.no_cache;
+        build_options.emulated |= cli_args.emulated;
+
+        let mut build_log = BuildLog::new(&config.logs_dir(config_dir), &target.slug);
+
+        let built_release = build_application(
+            client,
+            &application,
+            owner,
+            gzip,
+            &build_options,
+            &mut build_log,
+            events,
+        )
+        .await?;
+
+        // A headless build already reports its release, which a concurrent build
+        // could otherwise supersede as the latest one
+        let release = match built_release {
+            Some(release) => release,
+            None => get_latest_release(client, &application).await?,
+        };
+
+        let registry_credentials = match registration {
+            Some(ref registration) => RegistryCredentials::device(registration),
+            None => RegistryCredentials::user(
+                current_user
+                    .as_ref()
+                    .context("No authenticated user for registry login")?,
+                client.token(),
+            ),
+        };
+
+        // Each service image is downloaded once, however many copy rules use it
+        let mut image_dirs = HashMap::new();
+
+        for copy in &config.copy {
+            let service = copy.service.as_deref().or(target.service.as_deref());
+
+            if let Entry::Vacant(entry) = image_dirs.entry(service) {
+                let image_url =
+                    resolve_image_url(client, &release, registration.as_ref(), service).await?;
+                entry.insert(download_image(client, &image_url, &registry_credentials).await?);
+            }
+
+            copy_from_image(copy, &target.slug, image_dirs[&service].path())?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn resolve_image_url(
+    client: &BalenaClient,
+    release: &Release,
+    registration: Option<&DeviceRegistration>,
+    service: Option<&str>,
+) -> Result<String> {
+    let images = release.service_images();
+
+    if !images.is_empty() {
+        return Ok(select_service_image(&images, service)?.image_url.clone());
+    }
+
+    match registration {
+        Some(registration) => {
+            warn!(
+                "Release '{}' has no images, using the device state instead",
+                release.commit
+            );
+            wait_for_device_release(client, &registration.uuid, release, service).await
+        }
+        None => bail!("Release '{}' has no images", release.commit),
+    }
+}
+
+async fn get_config_applications(
+    client: &BalenaClient,
+    config_hash: &str,
+) -> Result<Vec<Application>> {
+    let mut applications = Vec::new();
+
+    for id in get_config_application_ids(client, config_hash).await? {
+        if let Some(application) = get_application_by_id(client, id).await? {
+            applications.push(application);
+        }
+    }
+
+    Ok(applications)
+}
+
+async fn list_applications(client: &BalenaClient, config_hash: &str) -> Result<()> {
+    let applications = get_config_applications(client, config_hash).await?;
+
+    if applications.is_empty() {
+        info!("No managed applications found");
+    }
+
+    for application in applications {
+        info!(
+            "Application '{}' ({}) for '{}'",
+            application.name, application.id, application.device_type
+        );
+    }
+
+    Ok(())
+}
+
+async fn clean_applications(client: &BalenaClient, config_hash: &str) -> Result<()> {
+    for application in get_config_applications(client, config_hash).await? {
+        delete_application(client, &application).await?;
+    }
+
+    Ok(())
+}
+
+async fn get_or_create_device(
+    client: &BalenaClient,
+    credentials: &mut CredentialsStore,
+    application: &Application,
+    slug: &str,
+    user: &User,
+) -> Result<DeviceRegistration> {
+    Ok(
+        if let Some(registration) =
+            get_device_registration(client, credentials, application, slug).await?
+        {
+            info!(
+                "Reusing device '{}' ({})",
+                registration.uuid, registration.id
+            );
+
+            registration
+        } else {
+            create_device(client, credentials, application, user, slug).await?
+        },
+    )
+}
@@ -1,5 +1,6 @@
 use std::io::{stdout, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::{Context, Result};
 
@@ -10,8 +11,12 @@ use crossterm::{
     style::{Color, Print, ResetColor, SetForegroundColor},
 };
 
+use crate::terminal::Terminal;
+
 static LOGGER: Logger = Logger;
 
+static COLOR: AtomicBool = AtomicBool::new(true);
+
 struct Logger;
 
 impl Log for Logger {
@@ -24,15 +29,25 @@ impl Log for Logger {
         make_ascii_titlecase(&mut origin);
         let formatted_origin = format_origin(&origin);
 
-        let _ = execute!(
-            stdout(),
-            SetForegroundColor(Color::Cyan),
-            Print(formatted_origin),
-            ResetColor,
-            Print(' '),
-            Print(record.args()),
-            Print('\n')
-        );
+        let _ = if COLOR.load(Ordering::Relaxed) {
+            execute!(
+                stdout(),
+                SetForegroundColor(Color::Cyan),
+                Print(formatted_origin),
+                ResetColor,
+                Print(' '),
+                Print(record.args()),
+                Print('\n')
+            )
+        } else {
+            execute!(
+                stdout(),
+                Print(formatted_origin),
+                Print(' '),
+                Print(record.args()),
+                Print('\n')
+            )
+        };
     }
 
     fn flush(&self) {}
@@ -68,7 +83,8 @@ fn make_ascii_titlecase(s: &mut str) {
     }
 }
 
-pub fn init() -> Result<()> {
+pub fn init(terminal: Terminal) -> Result<()> {
+    COLOR.store(terminal.color, Ordering::Relaxed);
     log::set_logger(&LOGGER).context("Logging initialization failed")?;
     log::set_max_level(LevelFilter::Info);
     Ok(())
@@ -0,0 +1,87 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::config::{Config, Target};
+
+const DEFAULT_APPLICATION_NAME: &str = "${project}-${slug}";
+
+pub fn application_name(config: &Config, config_name: &str, target: &Target) -> Result<String> {
+    let template = config
+        .application_name
+        .as_deref()
+        .unwrap_or(DEFAULT_APPLICATION_NAME);
+
+    let project = config.project.as_deref().unwrap_or(config_name);
+
+    expand_template(template, |placeholder| match placeholder {
+        "project" => Ok(project.to_string()),
+        "slug" => Ok(target.slug.clone()),
+        "user" => local_user(),
+        "hostname" => local_hostname(),
+        _ => Err(anyhow!("Unknown placeholder '${{{}}}'", placeholder)),
+    })
+    .context(format!(
+        "Expanding application name template '{}' failed",
+        template
+    ))
+}
+
+fn expand_template<F>(template: &str, mut resolve: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after.find('}').context("Unterminated placeholder")?;
+
+        expanded.push_str(&resolve(&after[..end])?);
+
+        rest = &after[end + 1..];
+    }
+
+    expanded.push_str(rest);
+
+    if expanded.is_empty() {
+        bail!("Application name is empty");
+    }
+
+    Ok(expanded)
+}
+
+fn local_user() -> Result<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .context("Cannot determine local user name")
+}
+
+fn local_hostname() -> Result<String> {
+    if let Ok(hostname) = std::env::var("HOSTNAME") {
+        return Ok(hostname);
+    }
+
+    if let Ok(hostname) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        return Ok(hostname.trim().to_string());
+    }
+
+    let output = std::process::Command::new("hostname")
+        .output()
+        .context("Cannot determine local host name")?;
+
+    if !output.status.success() {
+        bail!(
+            "Cannot determine local host name: hostname {}",
+            output.status
+        );
+    }
+
+    let hostname = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if hostname.is_empty() {
+        bail!("Cannot determine local host name: hostname printed nothing");
+    }
+
+    Ok(hostname)
+}
@@ -0,0 +1,42 @@
+use anyhow::{Context, Result};
+use log::info;
+
+use serde::Deserialize;
+
+use crate::api::{BalenaClient, Filter, Query, Response};
+
+const RESOURCE_ORGANIZATION: &str = "organization";
+
+#[derive(Debug, Deserialize)]
+pub struct Organization {
+    pub id: u64,
+    pub name: String,
+    pub handle: String,
+}
+
+fn get_organization_by_handle_endpoint(client: &BalenaClient, handle: &str) -> String {
+    Query::new(&client.resource(RESOURCE_ORGANIZATION))
+        .filter(Filter::eq("handle", handle))
+        .endpoint()
+}
+
+pub async fn get_organization_by_handle(
+    client: &BalenaClient,
+    handle: &str,
+) -> Result<Organization> {
+    info!("Getting organization by handle '{}'", handle);
+
+    let organization = client
+        .get_json::<Response<Organization>>(&get_organization_by_handle_endpoint(client, handle))
+        .await?
+        .data
+        .pop()
+        .context(format!("Organization '{}' not found", handle))?;
+
+    info!(
+        "Organization found '{}' ({})",
+        organization.name, organization.id
+    );
+
+    Ok(organization)
+}
@@ -1,49 +1,188 @@
-use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
 use log::info;
 
 use futures::future::try_join_all;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
+use crate::api::{check_status, BalenaClient};
 use crate::device::DeviceRegistration;
+use crate::retry::{is_transient, retry_if};
+use crate::user::User;
+
+const MANIFEST_V2: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    layers: Vec<Layer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Layer {
+    digest: String,
+}
+
+/// Downloaded blob does not hash to its digest, as after a truncated transfer
+#[derive(Debug)]
+struct DigestMismatch {
+    expected: String,
+    actual: String,
+}
+
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Layer digest mismatch (expected: {} / real: sha256:{})",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Credentials used for pulling release images from the registry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistryLogin {
+    /// Log in as a placeholder device with its device API key
+    #[default]
+    Device,
+    /// Log in as the authenticated user with the access token
+    User,
+}
+
+impl std::str::FromStr for RegistryLogin {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "device" => Ok(RegistryLogin::Device),
+            "user" => Ok(RegistryLogin::User),
+            _ => bail!("Unknown registry login '{}'", value),
+        }
+    }
+}
+
+pub struct RegistryCredentials {
+    username: String,
+    password: String,
+}
+
+impl RegistryCredentials {
+    pub fn device(registration: &DeviceRegistration) -> Self {
+        RegistryCredentials {
+            username: format!("d_{}", registration.uuid),
+            password: registration.api_key.clone(),
+        }
+    }
+
+    pub fn user(user: &User, token: &str) -> Self {
+        RegistryCredentials {
+            username: user.username.clone(),
+            password: token.to_string(),
+        }
+    }
+}
+
+struct ImageReference {
+    registry: String,
+    image: String,
+    reference: String,
+}
 
-fn parse_image_url(image_url: &str) -> Result<(String, String)> {
-    let registry_index = image_url.find('/').unwrap();
+enum Authorization {
+    Anonymous,
+    Basic(String, String),
+    Bearer(String),
+}
+
+struct RegistryClient<'a> {
+    http: &'a reqwest::Client,
+    base: String,
+    authorization: Authorization,
+}
+
+fn parse_image_url(image_url: &str) -> Result<ImageReference> {
+    let registry_index = image_url
+        .find('/')
+        .context(format!("No registry in image URL '{}'", image_url))?;
     let registry = image_url[..registry_index].to_string();
     let rest = &image_url[registry_index + 1..];
-    let image_index = rest.find('@').unwrap();
-    let image = rest[..image_index].to_string();
-    Ok((registry, image))
+    let (image, reference) = match rest.find('@') {
+        Some(digest_index) => (&rest[..digest_index], &rest[digest_index + 1..]),
+        None => (rest, "latest"),
+    };
+    Ok(ImageReference {
+        registry,
+        image: image.to_string(),
+        reference: reference.to_string(),
+    })
 }
 
-pub async fn download_image(image_url: &str, registration: &DeviceRegistration) -> Result<TempDir> {
-    let (registry, image) = parse_image_url(image_url)?;
-    let username = format!("d_{}", registration.uuid);
-
-    let client = dkregistry::v2::Client::configure()
-        .registry(&registry)
-        .insecure_registry(false)
-        .username(Some(username))
-        .password(Some(registration.api_key.clone()))
-        .build()
-        .unwrap();
+pub async fn download_image(
+    client: &BalenaClient,
+    image_url: &str,
+    credentials: &RegistryCredentials,
+) -> Result<TempDir> {
+    let reference = parse_image_url(image_url)?;
 
-    let dclient = authenticate_client(client, &image).await.unwrap();
+    let registry_client = RegistryClient::login(
+        client.http(),
+        client.registry_url(&reference.registry),
+        &reference.image,
+        &credentials.username,
+        &credentials.password,
+    )
+    .await
+    .context(format!(
+        "Logging in to registry '{}' failed",
+        reference.registry
+    ))?;
 
     info!("Downloading image manifest");
-    let manifest = dclient.get_manifest(&image, "latest").await.unwrap();
+    let manifest = registry_client
+        .get_manifest(&reference.image, &reference.reference)
+        .await
+        .context("Downloading image manifest failed")?;
 
-    let layers_digests = manifest.layers_digests(None).unwrap();
+    let layers_digests = manifest
+        .layers
+        .iter()
+        .map(|layer| layer.digest.as_str())
+        .collect::<Vec<_>>();
 
     info!(
         "Downloading {} layers. Please wait...",
         layers_digests.len()
     );
+    let registry_client = &registry_client;
+    let image = &reference.image;
     let blob_futures = layers_digests
         .iter()
-        .map(|layer_digest| dclient.get_blob(&image, &layer_digest))
+        .map(|layer_digest| async move {
+            let description = format!("Downloading layer {}", layer_digest);
+            retry_if(
+                client.retry_policy(),
+                &description,
+                is_retryable_download,
+                || registry_client.get_blob(image, layer_digest),
+            )
+            .await
+        })
         .collect::<Vec<_>>();
 
-    let blobs = try_join_all(blob_futures).await.unwrap();
+    let blobs = try_join_all(blob_futures).await?;
     info!("All layers downloaded");
 
     let temp_dir = TempDir::new().context("Creating temp directory for unpacking image failed")?;
@@ -53,27 +192,163 @@ pub async fn download_image(image_url: &str, registration: &DeviceRegistration)
     Ok(temp_dir)
 }
 
-pub async fn authenticate_client(
-    mut client: dkregistry::v2::Client,
-    image: &str,
-) -> Result<dkregistry::v2::Client, dkregistry::errors::Error> {
-    if !client.is_v2_supported().await? {
-        return Err("API v2 not supported".into());
+impl<'a> RegistryClient<'a> {
+    async fn login(
+        http: &'a reqwest::Client,
+        base: String,
+        image: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<RegistryClient<'a>> {
+        let response = http.get(&format!("{}/v2/", base)).send().await?;
+
+        if response.status().is_success() {
+            return Ok(RegistryClient {
+                http,
+                base,
+                authorization: Authorization::Anonymous,
+            });
+        }
+
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            bail!("API v2 not supported (status {})", response.status());
+        }
+
+        let challenge = response
+            .headers()
+            .get(reqwest::header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .context("No authentication challenge provided")?
+            .to_string();
+
+        let authorization = if let Some(params) = challenge.strip_prefix("Bearer ") {
+            let params = parse_challenge(params);
+            let realm = params.get("realm").context("No token realm provided")?;
+            let scope = format!("repository:{}:pull", image);
+
+            let mut query = vec![("scope", scope.as_str())];
+            if let Some(service) = params.get("service") {
+                query.push(("service", service.as_str()));
+            }
+
+            let response = http
+                .get(realm)
+                .query(&query)
+                .basic_auth(username, Some(password))
+                .send()
+                .await?;
+
+            let token = check_status(reqwest::Method::GET, response)
+                .await?
+                .json::<TokenResponse>()
+                .await?;
+
+            Authorization::Bearer(
+                token
+                    .token
+                    .or(token.access_token)
+                    .context("No token in registry token response")?,
+            )
+        } else {
+            Authorization::Basic(username.to_string(), password.to_string())
+        };
+
+        info!("Logged in to registry");
+
+        Ok(RegistryClient {
+            http,
+            base,
+            authorization,
+        })
     }
 
-    if client.is_auth(None).await? {
-        return Ok(client);
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self.http.get(&format!("{}/v2/{}", self.base, path));
+        match self.authorization {
+            Authorization::Anonymous => request,
+            Authorization::Basic(ref username, ref password) => {
+                request.basic_auth(username, Some(password))
+            }
+            Authorization::Bearer(ref token) => request.bearer_auth(token),
+        }
     }
 
-    let login_scope = format!("repository:{}:pull", image);
-    let token = client.login(&[&login_scope]).await?;
+    async fn get_manifest(&self, image: &str, reference: &str) -> Result<Manifest> {
+        let response = self
+            .get(&format!("{}/manifests/{}", image, reference))
+            .header(reqwest::header::ACCEPT, MANIFEST_V2)
+            .send()
+            .await?;
 
-    if !client.is_auth(Some(token.token())).await? {
-        Err("Logging in to registry failed".into())
-    } else {
-        info!("Logged in to registry");
-        Ok(client.set_token(Some(token.token())).clone())
+        Ok(check_status(reqwest::Method::GET, response)
+            .await?
+            .json::<Manifest>()
+            .await?)
     }
+
+    async fn get_blob(&self, image: &str, digest: &str) -> Result<Vec<u8>> {
+        let response = self
+            .get(&format!("{}/blobs/{}", image, digest))
+            .send()
+            .await?;
+
+        let blob = check_status(reqwest::Method::GET, response)
+            .await?
+            .bytes()
+            .await?
+            .to_vec();
+
+        verify_digest(&blob, digest)?;
+
+        Ok(blob)
+    }
+}
+
+fn parse_challenge(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut rest = params.trim();
+
+    while let Some(equals) = rest.find('=') {
+        let key = rest[..equals].trim().to_string();
+        rest = &rest[equals + 1..];
+
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"').map_or(rest.len(), |end| end + 1);
+            let value = rest[1..end].to_string();
+            rest = rest.get(end + 1..).unwrap_or("");
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+
+        parsed.insert(key, value);
+        rest = rest.trim_start_matches(&[',', ' '][..]);
+    }
+
+    parsed
+}
+
+fn verify_digest(blob: &[u8], digest: &str) -> Result<()> {
+    if let Some(expected) = digest.strip_prefix("sha256:") {
+        let actual = hex::encode(Sha256::digest(blob));
+        if actual != expected {
+            return Err(DigestMismatch {
+                expected: digest.to_string(),
+                actual,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+// A corrupted blob is downloaded again, like a transient failure
+fn is_retryable_download(err: &anyhow::Error) -> bool {
+    is_transient(err) || err.chain().any(|cause| cause.is::<DigestMismatch>())
 }
 
 fn unpack(layers: &[Vec<u8>], target_dir: &std::path::Path) -> Result<()> {
@@ -123,3 +398,69 @@ fn clean_whiteouts(
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    #[test]
+    fn parses_bearer_challenge() {
+        let params = parse_challenge(
+            "realm=\"https://api.balena-cloud.com/auth/v1/token\",service=\"registry2.balena-cloud.com\"",
+        );
+
+        assert_eq!(params.len(), 2);
+        assert_eq!(
+            params["realm"],
+            "https://api.balena-cloud.com/auth/v1/token"
+        );
+        assert_eq!(params["service"], "registry2.balena-cloud.com");
+    }
+
+    #[test]
+    fn parses_challenge_with_spaces_and_unquoted_values() {
+        let params = parse_challenge(
+            " realm=\"https://auth/token\", scope=\"repository:a/b:pull,push\", error=insufficient_scope ",
+        );
+
+        assert_eq!(params["realm"], "https://auth/token");
+        assert_eq!(params["scope"], "repository:a/b:pull,push");
+        assert_eq!(params["error"], "insufficient_scope");
+    }
+
+    #[test]
+    fn parses_challenge_with_unterminated_quote() {
+        let params = parse_challenge("realm=\"https://auth/token");
+
+        assert_eq!(params["realm"], "https://auth/token");
+    }
+
+    #[test]
+    fn ignores_challenge_without_parameters() {
+        assert!(parse_challenge("").is_empty());
+        assert!(parse_challenge("realm").is_empty());
+    }
+
+    #[test]
+    fn verifies_sha256_digest() {
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(b"layer")));
+
+        verify_digest(b"layer", &digest).unwrap();
+
+        let error = verify_digest(b"other", &digest).unwrap_err();
+        assert!(error.to_string().contains("Layer digest mismatch"));
+        assert!(is_retryable_download(&error));
+    }
+
+    #[test]
+    fn does_not_retry_permanent_download_errors() {
+        assert!(!is_retryable_download(&anyhow!("Unpacking layer failed")));
+    }
+
+    #[test]
+    fn skips_unknown_digest_algorithms() {
+        verify_digest(b"layer", "sha512:0000").unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+use serde::Deserialize;
+
+use crate::api::{BalenaClient, Filter, Order, Query, Response};
+use crate::application::Application;
+use crate::image::ServiceImage;
+
+const RESOURCE_RELEASE: &str = "release";
+
+const STATUS_SUCCESS: &str = "success";
+const STATUS_RUNNING: &str = "running";
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub id: u64,
+    pub commit: String,
+    pub status: String,
+    #[serde(default, rename = "contains__image")]
+    images: Vec<ReleaseImage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseImage {
+    image: Vec<Image>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Image {
+    #[serde(rename = "is_stored_at__image_location")]
+    location: String,
+    content_hash: Option<String>,
+    #[serde(default, rename = "is_a_build_of__service")]
+    services: Vec<Service>,
+    #[serde(default)]
+    build_log: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Service {
+    service_name: String,
+}
+
+impl Image {
+    fn service_name(&self) -> String {
+        self.services
+            .first()
+            .map(|service| service.service_name.clone())
+            .unwrap_or_default()
+    }
+
+    fn url(&self) -> String {
+        match self.content_hash {
+            Some(ref content_hash) => format!("{}@{}", self.location, content_hash),
+            None => self.location.clone(),
+        }
+    }
+}
+
+impl Release {
+    fn built_images(&self) -> impl Iterator<Item = &Image> {
+        self.images
+            .iter()
+            .flat_map(|release_image| release_image.image.iter())
+    }
+
+    pub fn service_images(&self) -> Vec<ServiceImage> {
+        self.built_images()
+            .map(|image| ServiceImage {
+                service_name: image.service_name(),
+                image_url: image.url(),
+            })
+            .collect()
+    }
+
+    /// Build logs of the release images, paired with their service names
+    pub fn build_logs(&self) -> Vec<(String, &str)> {
+        self.built_images()
+            .filter_map(|image| {
+                image
+                    .build_log
+                    .as_deref()
+                    .map(|build_log| (image.service_name(), build_log))
+            })
+            .collect()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == STATUS_SUCCESS
+    }
+}
+
+fn get_latest_release_endpoint(client: &BalenaClient, application_id: u64) -> String {
+    let image = Query::nested()
+        .select(&["is_stored_at__image_location", "content_hash"])
+        .expand(
+            "is_a_build_of__service",
+            Query::nested().select(&["service_name"]),
+        );
+
+    Query::new(&client.resource(RESOURCE_RELEASE))
+        .filter(Filter::eq("belongs_to__application", application_id))
+        .select(&["id", "commit", "status"])
+        .expand(
+            "contains__image",
+            Query::nested().select(&["id"]).expand("image", image),
+        )
+        .orderby("created_at", Order::Desc)
+        .top(1)
+        .endpoint()
+}
+
+fn get_release_endpoint(client: &BalenaClient, release_id: u64) -> String {
+    let image = Query::nested()
+        .select(&["is_stored_at__image_location", "content_hash", "build_log"])
+        .expand(
+            "is_a_build_of__service",
+            Query::nested().select(&["service_name"]),
+        );
+
+    Query::new(&client.resource(RESOURCE_RELEASE))
+        .filter(Filter::eq("id", release_id))
+        .select(&["id", "commit", "status"])
+        .expand(
+            "contains__image",
+            Query::nested().select(&["id"]).expand("image", image),
+        )
+        .endpoint()
+}
+
+async fn get_release(client: &BalenaClient, release_id: u64) -> Result<Release> {
+    client
+        .get_json::<Response<Release>>(&get_release_endpoint(client, release_id))
+        .await?
+        .data
+        .pop()
+        .context(format!("Release {} not found", release_id))
+}
+
+/// Polls the release until the build finishes, with the build logs of its images
+pub async fn wait_for_release(client: &BalenaClient, release_id: u64) -> Result<Release> {
+    info!("Waiting for release {} to finish building", release_id);
+
+    let started = Instant::now();
+
+    loop {
+        let release = get_release(client, release_id).await?;
+
+        if release.status != STATUS_RUNNING {
+            info!("Release {} finished ({})", release_id, release.status);
+            return Ok(release);
+        }
+
+        if started.elapsed() >= client.build_timeout() {
+            bail!(
+                "Release {} did not finish building within {} seconds",
+                release_id,
+                client.build_timeout().as_secs()
+            );
+        }
+
+        tokio::time::delay_for(client.poll_interval()).await;
+    }
+}
+
+pub async fn get_latest_release(
+    client: &BalenaClient,
+    application: &Application,
+) -> Result<Release> {
+    info!("Getting latest '{}' release", application.name);
+
+    let release = client
+        .get_json::<Response<Release>>(&get_latest_release_endpoint(client, application.id))
+        .await?
+        .data
+        .pop()
+        .context(format!("No releases found for '{}'", application.name))?;
+
+    if !release.is_success() {
+        bail!(
+            "Latest '{}' release {} is not successful (status: {})",
+            application.name,
+            release.commit,
+            release.status
+        );
+    }
+
+    info!("Release '{}' ({})", release.commit, release.id);
+
+    Ok(release)
+}
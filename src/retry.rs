@@ -0,0 +1,123 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+
+use serde::Deserialize;
+
+use crate::api::{ApiError, ApiErrorKind};
+
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_INITIAL_DELAY_MS: u64 = 500;
+const DEFAULT_MAX_DELAY_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_delay_ms: DEFAULT_INITIAL_DELAY_MS,
+            max_delay_ms: DEFAULT_MAX_DELAY_MS,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .initial_delay_ms
+            .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX));
+        let capped = std::cmp::min(exponential, self.max_delay_ms);
+
+        // Equal jitter: wait between half and the full backoff delay
+        let half = capped / 2;
+        Duration::from_millis(half + random_below(capped - half + 1))
+    }
+}
+
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, description: &str, operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    retry_if(policy, description, is_transient, operation).await
+}
+
+pub async fn retry_if<T, F, Fut, P>(
+    policy: &RetryPolicy,
+    description: &str,
+    should_retry: P,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+    P: Fn(&anyhow::Error) -> bool,
+{
+    let mut attempt = 0;
+
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < policy.max_retries && should_retry(&err) => {
+                let delay = retry_after(&err).unwrap_or_else(|| policy.backoff(attempt));
+
+                attempt += 1;
+
+                warn!(
+                    "{} failed: {}. Retrying in {:.1}s ({}/{})",
+                    description,
+                    err,
+                    delay.as_secs_f32(),
+                    attempt,
+                    policy.max_retries
+                );
+
+                tokio::time::delay_for(delay).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    for cause in err.chain() {
+        if let Some(api_error) = cause.downcast_ref::<ApiError>() {
+            return matches!(
+                api_error.kind,
+                ApiErrorKind::TooManyRequests | ApiErrorKind::Server
+            );
+        }
+
+        if let Some(reqwest_error) = cause.downcast_ref::<reqwest::Error>() {
+            return reqwest_error.is_timeout()
+                || reqwest_error.is_connect()
+                || reqwest_error.is_request()
+                || reqwest_error.is_body();
+        }
+    }
+
+    false
+}
+
+fn retry_after(err: &anyhow::Error) -> Option<Duration> {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<ApiError>())
+        .find_map(|api_error| api_error.retry_after)
+}
+
+fn random_below(bound: u64) -> u64 {
+    let mut buf = [0; 8];
+    if getrandom::getrandom(&mut buf).is_err() {
+        return 0;
+    }
+    u64::from_le_bytes(buf) % bound
+}
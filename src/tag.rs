@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use log::info;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::{BalenaClient, Filter, Query};
+
+const RESOURCE_APPLICATION_TAG: &str = "application_tag";
+
+pub const TAG_MANAGED: &str = "crosser:managed";
+pub const TAG_CONFIG: &str = "crosser:config";
+pub const TAG_VERSION: &str = "crosser:version";
+
+#[derive(Debug, Clone, Serialize)]
+struct ApplicationTagData {
+    application: u64,
+    tag_key: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ApplicationTag {
+    application: Reference,
+    tag_key: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Reference {
+    #[serde(rename = "__id")]
+    id: u64,
+}
+
+fn get_application_tags_query(client: &BalenaClient, application_id: u64) -> Query {
+    Query::new(&client.resource(RESOURCE_APPLICATION_TAG))
+        .filter(Filter::eq("application", application_id))
+}
+
+pub async fn get_application_tags(
+    client: &BalenaClient,
+    application_id: u64,
+) -> Result<HashMap<String, String>> {
+    info!("Getting application tags");
+
+    let tags = client
+        .get_all::<ApplicationTag>(&get_application_tags_query(client, application_id))
+        .await?;
+
+    Ok(tags
+        .into_iter()
+        .map(|tag| (tag.tag_key, tag.value))
+        .collect())
+}
+
+async fn store_application_tag(
+    client: &BalenaClient,
+    application_id: u64,
+    tag_key: &str,
+    value: &str,
+) -> Result<()> {
+    let tag_data = ApplicationTagData {
+        application: application_id,
+        tag_key: tag_key.to_string(),
+        value: value.to_string(),
+    };
+
+    client
+        .post(&client.resource(RESOURCE_APPLICATION_TAG), &tag_data)
+        .await
+        .context(format!("Storing `{}` application tag failed", tag_key))?;
+
+    info!("Stored `{}` application tag", tag_key);
+
+    Ok(())
+}
+
+pub async fn tag_managed_application(
+    client: &BalenaClient,
+    application_id: u64,
+    config_hash: &str,
+) -> Result<()> {
+    store_application_tag(client, application_id, TAG_MANAGED, "true").await?;
+    store_application_tag(client, application_id, TAG_CONFIG, config_hash).await?;
+    store_application_tag(
+        client,
+        application_id,
+        TAG_VERSION,
+        env!("CARGO_PKG_VERSION"),
+    )
+    .await
+}
+
+fn get_config_tags_query(client: &BalenaClient, config_hash: &str) -> Query {
+    Query::new(&client.resource(RESOURCE_APPLICATION_TAG))
+        .filter(Filter::eq("tag_key", TAG_CONFIG))
+        .filter(Filter::eq("value", config_hash))
+}
+
+pub async fn get_config_application_ids(
+    client: &BalenaClient,
+    config_hash: &str,
+) -> Result<Vec<u64>> {
+    info!("Getting applications tagged with config '{}'", config_hash);
+
+    let tags = client
+        .get_all::<ApplicationTag>(&get_config_tags_query(client, config_hash))
+        .await?;
+
+    let mut ids = tags
+        .into_iter()
+        .map(|tag| tag.application.id)
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+
+    Ok(ids)
+}
@@ -0,0 +1,92 @@
+use anyhow::{bail, Result};
+
+const ESCAPE: char = '\u{1b}';
+const BELL: char = '\u{7}';
+
+/// When to emit colors and cursor movements
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ColorMode {
+    /// Only on an interactive terminal without `NO_COLOR` set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => bail!("Unknown color mode '{}'", value),
+        }
+    }
+}
+
+/// Capabilities of the standard output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Terminal {
+    /// Replaced lines can be redrawn with cursor movements
+    pub interactive: bool,
+    pub color: bool,
+}
+
+impl Terminal {
+    pub fn detect(color_mode: ColorMode) -> Self {
+        let interactive = atty::is(atty::Stream::Stdout);
+
+        let color = match color_mode {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => interactive && !no_color(),
+        };
+
+        Terminal { interactive, color }
+    }
+}
+
+// https://no-color.org: any non-empty value disables colors
+fn no_color() -> bool {
+    matches!(std::env::var_os("NO_COLOR"), Some(value) if !value.is_empty())
+}
+
+/// Removes CSI and OSC escape sequences along with carriage returns
+pub fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            ESCAPE => match chars.next() {
+                // CSI: parameters and intermediates up to a final byte in '@'..='~'
+                Some('[') => {
+                    for ch in &mut chars {
+                        if ('@'..='~').contains(&ch) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: terminated by BEL or ESC \
+                Some(']') => {
+                    while let Some(ch) = chars.next() {
+                        if ch == BELL {
+                            break;
+                        }
+                        if ch == ESCAPE {
+                            chars.next_if_eq(&'\\');
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\r' => {}
+            _ => stripped.push(ch),
+        }
+    }
+
+    stripped
+}
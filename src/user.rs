@@ -0,0 +1,24 @@
+use anyhow::Result;
+use log::info;
+
+use serde::Deserialize;
+
+use crate::api::BalenaClient;
+
+const WHOAMI_ENDPOINT: &str = "user/v1/whoami";
+
+#[derive(Debug, Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub username: String,
+}
+
+pub async fn get_current_user(client: &BalenaClient) -> Result<User> {
+    info!("Getting authenticated user");
+
+    let user = client.get_json::<User>(WHOAMI_ENDPOINT).await?;
+
+    info!("Authenticated as '{}' ({})", user.username, user.id);
+
+    Ok(user)
+}
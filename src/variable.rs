@@ -1,81 +1,88 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use log::info;
 
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
-use crate::api::{get, post, Response};
+use crate::api::{BalenaClient, Filter, Query, Response};
 
-const ENDPOINT_DEVICE_VARIABLES: &str = "v5/device_environment_variable";
+const RESOURCE_DEVICE_VARIABLES: &str = "device_environment_variable";
 
 const API_KEY: &str = "API_KEY";
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct DeviceEnvironmentVariableData {
-    device: String,
-    name: String,
-    value: String,
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct Variable {
     id: u64,
     name: String,
     value: String,
 }
 
-fn get_device_environment_variable_endpoint(device_id: u64, name: &str) -> String {
-    format!(
-        "{}?$filter=device eq '{}' and name eq '{}'",
-        ENDPOINT_DEVICE_VARIABLES, device_id, name
-    )
+fn get_device_environment_variable_endpoint(
+    client: &BalenaClient,
+    device_id: u64,
+    name: &str,
+) -> String {
+    Query::new(&client.resource(RESOURCE_DEVICE_VARIABLES))
+        .filter(Filter::eq("device", device_id))
+        .filter(Filter::eq("name", name))
+        .endpoint()
 }
 
 async fn get_device_environment_variable(
-    token: &str,
+    client: &BalenaClient,
     device_id: u64,
     name: &str,
-) -> Result<Option<String>> {
+) -> Result<Option<Variable>> {
     info!("Getting device environment variable '{}'", name);
 
-    let mut variables = get(
-        token,
-        &get_device_environment_variable_endpoint(device_id, name),
+    let mut variables = client
+        .get_json::<Response<Variable>>(&get_device_environment_variable_endpoint(
+            client, device_id, name,
+        ))
+        .await?
+        .data;
+
+    Ok(variables.pop())
+}
+
+fn get_variable_id_endpoint(client: &BalenaClient, variable_id: u64) -> String {
+    format!(
+        "{}({})",
+        client.resource(RESOURCE_DEVICE_VARIABLES),
+        variable_id
     )
-    .await?
-    .json::<Response<Variable>>()
-    .await?
-    .data;
-
-    if let Some(variable) = variables.pop() {
-        Ok(Some(variable.value))
-    } else {
-        Ok(None)
-    }
 }
 
-async fn store_device_environment_variable(
-    token: &str,
-    device_id: u64,
-    name: &str,
-    value: &str,
+async fn delete_device_environment_variable(
+    client: &BalenaClient,
+    variable: &Variable,
 ) -> Result<()> {
-    let variable_data = DeviceEnvironmentVariableData {
-        device: format!("{}", device_id),
-        name: name.to_string(),
-        value: value.to_string(),
-    };
-
-    let _result = post(token, ENDPOINT_DEVICE_VARIABLES, &variable_data).await?;
+    client
+        .delete(&get_variable_id_endpoint(client, variable.id))
+        .await
+        .context(format!(
+            "Deleting `{}` device variable failed",
+            variable.name
+        ))?;
 
-    info!("Stored `{}` device variable", name);
+    info!("Deleted `{}` device variable", variable.name);
 
     Ok(())
 }
 
-pub async fn get_device_api_key(token: &str, device_id: u64) -> Result<Option<String>> {
-    get_device_environment_variable(token, device_id, API_KEY).await
+/// API key stored in a device variable by earlier versions
+pub async fn get_legacy_device_api_key(
+    client: &BalenaClient,
+    device_id: u64,
+) -> Result<Option<String>> {
+    Ok(get_device_environment_variable(client, device_id, API_KEY)
+        .await?
+        .map(|variable| variable.value))
 }
 
-pub async fn store_device_api_key(token: &str, device_id: u64, value: &str) -> Result<()> {
-    store_device_environment_variable(token, device_id, API_KEY, value).await
+pub async fn delete_legacy_device_api_key(client: &BalenaClient, device_id: u64) -> Result<()> {
+    if let Some(variable) = get_device_environment_variable(client, device_id, API_KEY).await? {
+        delete_device_environment_variable(client, &variable).await?;
+    }
+
+    Ok(())
 }
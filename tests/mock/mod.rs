@@ -0,0 +1,940 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+const REGISTRY_TOKEN: &str = "mock-registry-token";
+const BUILD_CHUNK_SIZE: usize = 7;
+const HEADLESS_POLLS: u32 = 3;
+
+pub struct MockBalena {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    registry: String,
+    next_id: u64,
+    resources: HashMap<String, Vec<Value>>,
+    services: Vec<String>,
+    built: HashMap<u64, Vec<(String, String)>>,
+    releases: HashMap<u64, u64>,
+    running: HashMap<u64, (u32, &'static str)>,
+    omit_release_images: bool,
+    device_state_lag: u32,
+    layers: Vec<(String, Vec<u8>)>,
+    uploads: Vec<Vec<String>>,
+    builds: Vec<HashMap<String, String>>,
+    queries: Vec<(String, HashMap<String, String>)>,
+    pulls: Vec<String>,
+    fail_builds: bool,
+    no_native_builder: bool,
+    transient_failures: u32,
+}
+
+impl MockBalena {
+    pub async fn start(files: &[(&str, &str)]) -> Self {
+        let layer = tar_gz_layer(files);
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(&layer)));
+
+        let mut resources = HashMap::new();
+        resources.insert(
+            "device_type".to_string(),
+            vec![
+                json!({ "id": 101, "slug": "raspberrypi3" }),
+                json!({ "id": 102, "slug": "raspberrypi4-64" }),
+            ],
+        );
+
+        let state = Arc::new(Mutex::new(State {
+            next_id: 1,
+            resources,
+            services: vec!["main".to_string()],
+            layers: vec![(digest, layer)],
+            ..Default::default()
+        }));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| handle(state.clone(), request))) }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        state.lock().unwrap().registry = addr.to_string();
+
+        MockBalena { addr, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn resources(&self, resource: &str) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        state.resources.get(resource).cloned().unwrap_or_default()
+    }
+
+    pub fn add_resource(&self, resource: &str, value: Value) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        match value {
+            Value::Object(object) => state.insert(resource, object)["id"].as_u64().unwrap(),
+            _ => panic!("Resource must be a JSON object"),
+        }
+    }
+
+    pub fn uploads(&self) -> Vec<Vec<String>> {
+        self.state.lock().unwrap().uploads.clone()
+    }
+
+    pub fn pulls(&self) -> Vec<String> {
+        self.state.lock().unwrap().pulls.clone()
+    }
+
+    pub fn builds(&self) -> Vec<HashMap<String, String>> {
+        self.state.lock().unwrap().builds.clone()
+    }
+
+    /// Query options of the listings of a resource, in request order
+    pub fn queries(&self, resource: &str) -> Vec<HashMap<String, String>> {
+        let state = self.state.lock().unwrap();
+        state
+            .queries
+            .iter()
+            .filter(|(listed, _)| listed == resource)
+            .map(|(_, query)| query.clone())
+            .collect()
+    }
+
+    pub fn set_services(&self, services: &[&str]) {
+        self.state.lock().unwrap().services =
+            services.iter().map(|service| service.to_string()).collect();
+    }
+
+    pub fn omit_release_images(&self) {
+        self.state.lock().unwrap().omit_release_images = true;
+    }
+
+    /// Device state keeps reporting no release for the given number of polls
+    pub fn lag_device_state(&self, polls: u32) {
+        self.state.lock().unwrap().device_state_lag = polls;
+    }
+
+    /// Builds fail unless emulated, as for an architecture without native builders
+    pub fn no_native_builder(&self) {
+        self.state.lock().unwrap().no_native_builder = true;
+    }
+
+    pub fn fail_builds(&self) {
+        self.state.lock().unwrap().fail_builds = true;
+    }
+
+    pub fn fail_next_requests(&self, count: u32) {
+        self.state.lock().unwrap().transient_failures = count;
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<State>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().trim_start_matches('/').to_string();
+    let query = parse_query(request.uri().query().unwrap_or(""));
+    let authorization = request
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map(|bytes| bytes.to_vec())
+        .unwrap_or_default();
+
+    let mut state = state.lock().unwrap();
+
+    if state.transient_failures > 0 && method == Method::GET {
+        state.transient_failures -= 1;
+        return Ok(text(StatusCode::BAD_GATEWAY, "Bad gateway"));
+    }
+
+    if path != "v2/" && path != "auth/v1/token" && authorization.is_none() {
+        return Ok(text(StatusCode::UNAUTHORIZED, "Unauthorized"));
+    }
+
+    let response = if let Some(resource) = path
+        .strip_prefix("v5/")
+        .or_else(|| path.strip_prefix("v6/"))
+    {
+        match method {
+            Method::GET => {
+                if resource == "release" {
+                    state.advance_releases();
+                }
+                state.queries.push((resource.to_string(), query.clone()));
+                state.list(resource, &query)
+            }
+            Method::POST => state.create(resource, &body),
+            Method::PATCH => state.update(resource, &body),
+            Method::DELETE => state.delete(resource),
+            _ => text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+        }
+    } else if path == "user/v1/whoami" {
+        json_response(StatusCode::OK, &json!({ "id": 1, "username": "mock" }))
+    } else if let Some(device_id) = path
+        .strip_prefix("api-key/device/")
+        .and_then(|rest| rest.strip_suffix("/device-key"))
+    {
+        json_response(
+            StatusCode::OK,
+            &json!(format!("key-regenerated-{}", device_id)),
+        )
+    } else if path == "device/register" && method == Method::POST {
+        state.register(&body)
+    } else if let Some(uuid) = path
+        .strip_prefix("device/v2/")
+        .and_then(|rest| rest.strip_suffix("/state"))
+    {
+        state.device_state(uuid)
+    } else if path == "v3/build" && method == Method::POST {
+        state.build(&query, &body)
+    } else if path == "v2/" {
+        let mut response = text(StatusCode::UNAUTHORIZED, "Unauthorized");
+        let challenge = format!(
+            "Bearer realm=\"http://{}/auth/v1/token\",service=\"mock-registry\"",
+            state.registry
+        );
+        response
+            .headers_mut()
+            .insert(hyper::header::WWW_AUTHENTICATE, challenge.parse().unwrap());
+        response
+    } else if path == "auth/v1/token" {
+        match authorization {
+            Some(ref value) if value.starts_with("Basic ") => {
+                json_response(StatusCode::OK, &json!({ "token": REGISTRY_TOKEN }))
+            }
+            _ => text(StatusCode::UNAUTHORIZED, "Unauthorized"),
+        }
+    } else if let Some(rest) = path.strip_prefix("v2/") {
+        if authorization.as_deref() != Some(&format!("Bearer {}", REGISTRY_TOKEN)) {
+            text(StatusCode::UNAUTHORIZED, "Unauthorized")
+        } else if let Some(index) = rest.find("/manifests/") {
+            let image = rest[..index].to_string();
+            state.pulls.push(image);
+            state.manifest()
+        } else if let Some(index) = rest.find("/blobs/") {
+            state.blob(&rest[index + "/blobs/".len()..])
+        } else {
+            text(StatusCode::NOT_FOUND, "Not found")
+        }
+    } else {
+        text(StatusCode::NOT_FOUND, "Not found")
+    };
+
+    Ok(response)
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn insert(&mut self, resource: &str, mut object: Map<String, Value>) -> Value {
+        let id = self.next_id();
+        object.insert("id".to_string(), json!(id));
+        let value = Value::Object(object);
+        self.resources
+            .entry(resource.to_string())
+            .or_default()
+            .push(value.clone());
+        value
+    }
+
+    fn list(&self, resource: &str, query: &HashMap<String, String>) -> Response<Body> {
+        let mut items = self
+            .resources
+            .get(resource)
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|item| match query.get("$filter") {
+                Some(filter) => matches_filter(item, filter),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(orderby) = query.get("$orderby") {
+            items.sort_by_key(|item| item["id"].as_u64());
+            if orderby.ends_with(" desc") {
+                items.reverse();
+            }
+        }
+
+        let skip = query
+            .get("$skip")
+            .and_then(|skip| skip.parse().ok())
+            .unwrap_or(0);
+        let top = query
+            .get("$top")
+            .and_then(|top| top.parse().ok())
+            .unwrap_or(usize::MAX);
+
+        let projection = Projection::parse(query);
+
+        let items = items
+            .iter()
+            .skip(skip)
+            .take(top)
+            .map(|item| projection.apply(item))
+            .collect::<Vec<_>>();
+
+        json_response(StatusCode::OK, &json!({ "d": items }))
+    }
+
+    fn create(&mut self, resource: &str, body: &[u8]) -> Response<Body> {
+        let mut object = match serde_json::from_slice::<Map<String, Value>>(body) {
+            Ok(object) => object,
+            Err(_) => return text(StatusCode::BAD_REQUEST, "Malformed JSON"),
+        };
+
+        if resource == "application" {
+            let name = object["app_name"].clone();
+            let taken = self
+                .resources
+                .get(resource)
+                .into_iter()
+                .flatten()
+                .any(|item| item["app_name"] == name);
+            if taken {
+                return text(StatusCode::CONFLICT, "Unique key constraint violated");
+            }
+            object.insert("user".to_string(), json!([{ "id": 1, "username": "mock" }]));
+
+            // Applications of an organization are owned by its handle instead of the user
+            let owner = match object.get("organization").cloned() {
+                Some(organization_id) => {
+                    let handle = self
+                        .resources
+                        .get("organization")
+                        .into_iter()
+                        .flatten()
+                        .find(|organization| organization["id"] == organization_id)
+                        .map(|organization| organization["handle"].clone());
+                    match handle {
+                        Some(Value::String(handle)) => {
+                            object.insert(
+                                "organization".to_string(),
+                                json!({ "__id": organization_id }),
+                            );
+                            handle
+                        }
+                        _ => return text(StatusCode::BAD_REQUEST, "Unknown organization"),
+                    }
+                }
+                None => "mock".to_string(),
+            };
+            let slug = format!(
+                "{}/{}",
+                owner,
+                name.as_str().unwrap_or_default().to_lowercase()
+            );
+            object.insert("slug".to_string(), json!(slug));
+
+            // Store the device type relation expanded, as queried by the v6 client
+            if let Some(device_type_id) = object.get("is_for__device_type").cloned() {
+                let device_type = self
+                    .resources
+                    .get("device_type")
+                    .into_iter()
+                    .flatten()
+                    .find(|device_type| device_type["id"] == device_type_id)
+                    .cloned();
+                match device_type {
+                    Some(device_type) => {
+                        object.insert("is_for__device_type".to_string(), json!([device_type]));
+                    }
+                    None => return text(StatusCode::BAD_REQUEST, "Unknown device type"),
+                }
+            }
+        } else if resource == "application_tag" {
+            let application = object["application"].clone();
+            object.insert("application".to_string(), json!({ "__id": application }));
+        }
+
+        let value = self.insert(resource, object);
+
+        json_response(StatusCode::CREATED, &value)
+    }
+
+    fn update(&mut self, resource: &str, body: &[u8]) -> Response<Body> {
+        let (resource, id) = match parse_resource_id(resource) {
+            Some(parsed) => parsed,
+            None => return text(StatusCode::BAD_REQUEST, "Missing resource id"),
+        };
+
+        let changes = match serde_json::from_slice::<Map<String, Value>>(body) {
+            Ok(changes) => changes,
+            Err(_) => return text(StatusCode::BAD_REQUEST, "Malformed JSON"),
+        };
+
+        let items = self.resources.entry(resource).or_default();
+        match items.iter_mut().find(|item| item["id"] == json!(id)) {
+            Some(Value::Object(item)) => {
+                item.extend(changes);
+                text(StatusCode::OK, "OK")
+            }
+            _ => text(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    fn delete(&mut self, resource: &str) -> Response<Body> {
+        let (resource, id) = match parse_resource_id(resource) {
+            Some(parsed) => parsed,
+            None => return text(StatusCode::BAD_REQUEST, "Missing resource id"),
+        };
+
+        let items = self.resources.entry(resource.clone()).or_default();
+        let before = items.len();
+        items.retain(|item| item["id"] != json!(id));
+
+        if items.len() == before {
+            return text(StatusCode::NOT_FOUND, "Not found");
+        }
+
+        if resource == "application" {
+            for (dependent, field) in &[
+                ("application_tag", "application"),
+                ("device", "belongs_to__application"),
+            ] {
+                if let Some(items) = self.resources.get_mut(*dependent) {
+                    items.retain(|item| item[*field]["__id"] != json!(id));
+                }
+            }
+        }
+
+        text(StatusCode::OK, "OK")
+    }
+
+    fn register(&mut self, body: &[u8]) -> Response<Body> {
+        let request = match serde_json::from_slice::<Value>(body) {
+            Ok(request) => request,
+            Err(_) => return text(StatusCode::BAD_REQUEST, "Malformed JSON"),
+        };
+
+        let uuid = request["uuid"].as_str().unwrap_or_default().to_string();
+        let mut device = Map::new();
+        device.insert("uuid".to_string(), json!(uuid));
+        device.insert("device_name".to_string(), json!(uuid));
+        device.insert("device_type".to_string(), request["device_type"].clone());
+        device.insert(
+            "belongs_to__application".to_string(),
+            json!({ "__id": request["application"] }),
+        );
+
+        let device = self.insert("device", device);
+        let api_key = format!("key-{}", uuid);
+
+        json_response(
+            StatusCode::CREATED,
+            &json!({ "id": device["id"], "uuid": uuid, "api_key": api_key }),
+        )
+    }
+
+    fn device_state(&mut self, uuid: &str) -> Response<Body> {
+        let device = self
+            .resources
+            .get("device")
+            .and_then(|devices| devices.iter().find(|device| device["uuid"] == json!(uuid)));
+
+        let device = match device {
+            Some(device) => device,
+            None => return text(StatusCode::NOT_FOUND, "Device not found"),
+        };
+
+        let application_id = device["belongs_to__application"]["__id"]
+            .as_u64()
+            .unwrap_or_default();
+
+        let lagging = self.device_state_lag > 0;
+        if lagging {
+            self.device_state_lag -= 1;
+        }
+
+        let release_id = self
+            .releases
+            .get(&application_id)
+            .filter(|_| !lagging)
+            .copied();
+
+        let services = self
+            .built
+            .get(&application_id)
+            .filter(|_| !lagging)
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .map(|(index, (service, image))| {
+                let service = json!({ "serviceName": service, "image": image });
+                ((index + 1).to_string(), service)
+            })
+            .collect::<Map<String, Value>>();
+
+        json_response(
+            StatusCode::OK,
+            &json!({
+                "local": {
+                    "name": device["device_name"],
+                    "apps": {
+                        application_id.to_string(): {
+                            "releaseId": release_id,
+                            "commit": release_id.map(release_commit),
+                            "services": services,
+                        }
+                    }
+                }
+            }),
+        )
+    }
+
+    fn build(&mut self, query: &HashMap<String, String>, body: &[u8]) -> Response<Body> {
+        let app_name = query.get("app").cloned().unwrap_or_default();
+        let application = self.resources.get("application").and_then(|applications| {
+            applications
+                .iter()
+                .find(|application| application["app_name"] == json!(app_name))
+        });
+
+        let application_id = match application {
+            Some(application) => application["id"].as_u64().unwrap_or_default(),
+            None => return text(StatusCode::NOT_FOUND, "Application not found"),
+        };
+
+        self.uploads.push(archive_entries(body));
+        self.builds.push(query.clone());
+
+        let headless = query.get("headless").map(String::as_str) == Some("true");
+
+        if self.no_native_builder && query.get("emulated").map(String::as_str) != Some("true") {
+            let message = "No native builders available for this architecture";
+            let reply = if headless {
+                json!({ "started": false, "message": message })
+            } else {
+                json!([{ "message": message }, { "isSuccess": false }])
+            };
+            return json_response(StatusCode::OK, &reply);
+        }
+
+        let mut events = vec![
+            json!({ "message": "Uploading source" }),
+            json!({ "message": "Building 🚀" }),
+            json!({ "message": "Step 1/2", "replace": true }),
+            json!({ "resource": "cursor", "value": "erase" }),
+        ];
+
+        let release_id = self.next_id();
+        let digest = self.layers[0].0.clone();
+        let images = self
+            .services
+            .iter()
+            .map(|service| {
+                let location = format!("{}/v2/image{}-{}", self.registry, release_id, service);
+                (service.clone(), location)
+            })
+            .collect::<Vec<_>>();
+
+        if self.fail_builds {
+            events.push(json!({ "message": "Build failed" }));
+            events.push(json!({ "isSuccess": false }));
+        } else {
+            let built = images
+                .iter()
+                .map(|(service, location)| (service.clone(), format!("{}@{}", location, digest)))
+                .collect();
+            self.built.insert(application_id, built);
+            self.releases.insert(application_id, release_id);
+            events.push(json!({ "type": "metadata", "resource": "phase", "value": "push" }));
+            for (service, location) in &images {
+                events.push(json!({
+                    "resource": "image",
+                    "value": { "service": service, "location": location },
+                }));
+            }
+            events.push(json!({ "message": "\u{1b}[32mBuild succeeded\u{1b}[0m" }));
+            events.push(json!({ "isSuccess": true }));
+        }
+
+        let release_images = images
+            .iter()
+            .filter(|_| !self.omit_release_images)
+            .map(|(service, location)| {
+                json!({
+                    "id": release_id,
+                    "image": [{
+                        "is_stored_at__image_location": location,
+                        "content_hash": digest,
+                        "is_a_build_of__service": [{ "service_name": service }],
+                        "build_log": format!("Step 1/2\nBuilt {}\n", service),
+                    }],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let status = if self.fail_builds {
+            "failed"
+        } else {
+            "success"
+        };
+
+        // Headless releases keep running for a few status polls
+        if headless {
+            self.running.insert(release_id, (HEADLESS_POLLS, status));
+        }
+
+        let release = json!({
+            "id": release_id,
+            "commit": release_commit(release_id),
+            "status": if headless { "running" } else { status },
+            "created_at": format!("2020-01-01T00:00:{:02}.000Z", release_id),
+            "belongs_to__application": { "__id": application_id },
+            "contains__image": release_images,
+        });
+        self.resources
+            .entry("release".to_string())
+            .or_default()
+            .push(release);
+
+        if headless {
+            return json_response(
+                StatusCode::OK,
+                &json!({ "started": true, "releaseId": release_id }),
+            );
+        }
+
+        let stream = serde_json::to_string(&events)
+            .unwrap()
+            .replace("},{", "},\n{");
+        let chunks = stream
+            .into_bytes()
+            .chunks(BUILD_CHUNK_SIZE)
+            .map(|chunk| Ok::<_, Infallible>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+
+        Response::new(Body::wrap_stream(futures::stream::iter(chunks)))
+    }
+
+    fn advance_releases(&mut self) {
+        let mut finished = Vec::new();
+        for (release_id, (polls, status)) in self.running.iter_mut() {
+            *polls = polls.saturating_sub(1);
+            if *polls == 0 {
+                finished.push((*release_id, *status));
+            }
+        }
+
+        for (release_id, status) in finished {
+            self.running.remove(&release_id);
+            if let Some(release) = self
+                .resources
+                .get_mut("release")
+                .into_iter()
+                .flatten()
+                .find(|release| release["id"] == json!(release_id))
+            {
+                release["status"] = json!(status);
+            }
+        }
+    }
+
+    fn manifest(&self) -> Response<Body> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|(digest, layer)| {
+                json!({
+                    "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+                    "size": layer.len(),
+                    "digest": digest,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json_response(
+            StatusCode::OK,
+            &json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "layers": layers,
+            }),
+        )
+    }
+
+    fn blob(&self, digest: &str) -> Response<Body> {
+        match self
+            .layers
+            .iter()
+            .find(|(layer_digest, _)| layer_digest == digest)
+        {
+            Some((_, layer)) => Response::new(Body::from(layer.clone())),
+            None => text(StatusCode::NOT_FOUND, "Blob not found"),
+        }
+    }
+}
+
+/// `$select` and `$expand` options of a query level, as applied to stored items
+///
+/// Related items are stored inline as arrays, so like the real API only expanded
+/// arrays are returned, while plain fields are limited to the selected ones.
+#[derive(Default)]
+struct Projection {
+    select: Option<Vec<String>>,
+    expand: Vec<(String, Projection)>,
+}
+
+impl Projection {
+    fn parse(options: &HashMap<String, String>) -> Self {
+        let select = options.get("$select").map(|select| {
+            select
+                .split(',')
+                .map(|field| field.trim().to_string())
+                .collect()
+        });
+
+        let expand = options
+            .get("$expand")
+            .map(|expand| {
+                split_top_level(expand, ',')
+                    .into_iter()
+                    .map(|field| match field.find('(') {
+                        Some(open) => {
+                            let nested = field[open + 1..].trim_end_matches(')');
+                            let nested = split_top_level(nested, ';')
+                                .into_iter()
+                                .filter_map(|option| {
+                                    let mut split = option.splitn(2, '=');
+                                    Some((split.next()?.to_string(), split.next()?.to_string()))
+                                })
+                                .collect();
+                            (field[..open].to_string(), Projection::parse(&nested))
+                        }
+                        None => (field.to_string(), Projection::default()),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Projection { select, expand }
+    }
+
+    fn apply(&self, item: &Value) -> Value {
+        let object = match item {
+            Value::Object(object) => object,
+            _ => return item.clone(),
+        };
+
+        let mut projected = Map::new();
+
+        for (field, value) in object {
+            let expanded = self.expand.iter().find(|(name, _)| name == field);
+
+            match (expanded, value) {
+                (Some((_, nested)), Value::Array(items)) => {
+                    let items = items.iter().map(|item| nested.apply(item)).collect();
+                    projected.insert(field.clone(), Value::Array(items));
+                }
+                (Some(_), _) => {
+                    projected.insert(field.clone(), value.clone());
+                }
+                (None, Value::Array(_)) => {}
+                (None, _) => {
+                    let selected = match self.select {
+                        Some(ref select) => select.contains(field),
+                        None => true,
+                    };
+                    if selected {
+                        projected.insert(field.clone(), value.clone());
+                    }
+                }
+            }
+        }
+
+        Value::Object(projected)
+    }
+}
+
+fn split_top_level(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (index, ch) in value.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ if ch == separator && depth == 0 => {
+                parts.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts.into_iter().filter(|part| !part.is_empty()).collect()
+}
+
+fn matches_filter(item: &Value, filter: &str) -> bool {
+    filter.split(" and ").all(|condition| {
+        let mut parts = condition.splitn(3, ' ');
+        let (field, operator, literal) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(field), Some(operator), Some(literal)) => (field, operator, literal),
+            _ => return false,
+        };
+
+        let expected = if literal.starts_with('\'') && literal.ends_with('\'') {
+            literal[1..literal.len() - 1].replace("''", "'")
+        } else {
+            literal.to_string()
+        };
+
+        let actual = match &item[field] {
+            Value::String(value) => value.clone(),
+            Value::Object(object) => object
+                .get("__id")
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            value => value.to_string(),
+        };
+
+        operator == "eq" && actual == expected
+    })
+}
+
+fn parse_resource_id(resource: &str) -> Option<(String, u64)> {
+    let open = resource.find('(')?;
+    let id = resource[open + 1..].strip_suffix(')')?.parse().ok()?;
+    Some((resource[..open].to_string(), id))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut split = pair.splitn(2, '=');
+            let key = percent_decode(split.next().unwrap_or_default());
+            let value = percent_decode(split.next().unwrap_or_default());
+            (key, value)
+        })
+        .collect()
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' if index + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[index + 1..index + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => decoded.push(byte),
+                    Err(_) => decoded.extend_from_slice(&bytes[index..index + 3]),
+                }
+                index += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn tar_gz_layer(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(
+                &mut header,
+                path.trim_start_matches('/'),
+                contents.as_bytes(),
+            )
+            .unwrap();
+    }
+
+    let data = builder.into_inner().unwrap();
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn archive_entries(gzip: &[u8]) -> Vec<String> {
+    let mut data = Vec::new();
+    if GzDecoder::new(gzip).read_to_end(&mut data).is_err() {
+        return Vec::new();
+    }
+
+    let mut archive = tar::Archive::new(data.as_slice());
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry
+                .path()
+                .ok()
+                .map(|path| path.to_string_lossy().to_string())
+        })
+        .collect()
+}
+
+fn release_commit(release_id: u64) -> String {
+    format!("{:032x}", release_id)
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(value.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        "application/json".parse().unwrap(),
+    );
+    response
+}
+
+fn text(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.to_string()));
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,1052 @@
+mod mock;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use crosser::build_event::{BuildEvent, BuildEventSink};
+use crosser::cli::{CliArgs, Command};
+
+use mock::MockBalena;
+
+// The pipeline changes the process working directory, so runs must not overlap
+static PIPELINE: Mutex<()> = Mutex::new(());
+
+const ARTIFACT: &str = "built artifact\n";
+
+const CONFIG: &str = "
+source: src
+copy:
+  from_image:
+    - /app/*
+  to: output
+targets:
+  - slug: rpi
+    device_type: raspberrypi4-64
+    dockerfile: Dockerfile
+retry:
+  initial_delay_ms: 10
+  max_delay_ms: 50
+";
+
+fn read_credentials(project: &Path) -> Value {
+    let path = project.join("credentials/credentials.json");
+    serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+}
+
+const COMPOSE: &str = "
+version: '2'
+services:
+  main:
+    build: .
+  sidecar:
+    build: .
+";
+
+struct PipelineRun {
+    mock: MockBalena,
+    project: TempDir,
+    results: Vec<Result<()>>,
+    events: Vec<BuildEvent>,
+}
+
+#[derive(Default)]
+struct RecordedEvents {
+    events: Vec<BuildEvent>,
+}
+
+impl BuildEventSink for RecordedEvents {
+    fn event(&mut self, event: &BuildEvent) -> Result<()> {
+        self.events.push(event.clone());
+        Ok(())
+    }
+}
+
+fn create_project(config: &str) -> (TempDir, PathBuf) {
+    let project = TempDir::new().unwrap();
+    let root = project.path();
+
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(
+        root.join("src").join("main.c"),
+        "int main() { return 0; }\n",
+    )
+    .unwrap();
+    fs::write(root.join("Dockerfile"), "FROM scratch\nCOPY . /app\n").unwrap();
+    fs::write(root.join("services.yml"), COMPOSE).unwrap();
+
+    let config_path = root.join("crosser.yml");
+    fs::write(&config_path, config).unwrap();
+
+    (project, config_path)
+}
+
+fn run_pipeline<F>(config: &str, runs: usize, prepare: F) -> PipelineRun
+where
+    F: FnOnce(&MockBalena),
+{
+    let runs = (0..runs).map(|_| CliArgs::default()).collect();
+    run_pipeline_with_args(config, prepare, runs)
+}
+
+fn run_pipeline_with_args<F>(config: &str, prepare: F, runs: Vec<CliArgs>) -> PipelineRun
+where
+    F: FnOnce(&MockBalena),
+{
+    let _guard = PIPELINE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let (project, config_path) = create_project(config);
+    let credentials_dir = project.path().join("credentials");
+    let original_dir = std::env::current_dir().unwrap();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let (mock, results, events) = runtime.block_on(async {
+        let mock = MockBalena::start(&[("/app/artifact.txt", ARTIFACT)]).await;
+
+        prepare(&mock);
+
+        let mut events = RecordedEvents::default();
+        let mut results = Vec::new();
+        for args in runs {
+            // Copying never overwrites artifacts, so every run starts without earlier ones
+            let _ = fs::remove_dir_all(project.path().join("output"));
+
+            let cli_args = CliArgs {
+                command: args.command,
+                config: config_path.to_string_lossy().to_string(),
+                token: "mock-token".to_string(),
+                api_url: Some(mock.url()),
+                builder_url: Some(mock.url()),
+                insecure_registry: true,
+                recreate_mismatched: args.recreate_mismatched,
+                no_cache: args.no_cache,
+                emulated: args.emulated,
+                credentials_dir: Some(credentials_dir.to_string_lossy().to_string()),
+                credentials_passphrase: args.credentials_passphrase,
+                ..Default::default()
+            };
+            results.push(crosser::run_with_build_events(cli_args, &mut events).await);
+        }
+
+        (mock, results, events.events)
+    });
+
+    std::env::set_current_dir(&original_dir).unwrap();
+
+    PipelineRun {
+        mock,
+        project,
+        results,
+        events,
+    }
+}
+
+fn run_binary(color: Option<&str>) -> (PipelineRun, String) {
+    let (project, config_path) = create_project(CONFIG);
+
+    // The mock keeps serving from the runtime workers while the binary runs
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let mock = runtime.block_on(MockBalena::start(&[("/app/artifact.txt", ARTIFACT)]));
+
+    let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_crosser"));
+    command
+        .arg("--config")
+        .arg(&config_path)
+        .arg("--credentials-dir")
+        .arg(project.path().join("credentials"))
+        .args(["--token", "mock-token", "--insecure-registry"])
+        .args(["--api-url", &mock.url(), "--builder-url", &mock.url()])
+        .env_remove("NO_COLOR");
+    if let Some(color) = color {
+        command.arg("--color").arg(color);
+    }
+
+    let output = command.output().unwrap();
+    let result = if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    };
+
+    let run = PipelineRun {
+        mock,
+        project,
+        results: vec![result],
+        events: Vec::new(),
+    };
+
+    (run, String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn prints_plain_output_without_terminal() {
+    let (run, stdout) = run_binary(None);
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert!(stdout.contains("Build succeeded"));
+    assert!(!stdout.contains('\u{1b}'));
+}
+
+#[test]
+fn forces_colors_without_terminal() {
+    let (run, stdout) = run_binary(Some("always"));
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert!(stdout.contains("\u{1b}[32mBuild succeeded"));
+}
+
+#[test]
+fn builds_and_copies_artifacts() {
+    let run = run_pipeline(CONFIG, 1, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let artifact = run.project.path().join("output/rpi/artifact.txt");
+    assert_eq!(fs::read_to_string(artifact).unwrap(), ARTIFACT);
+
+    let uploads = run.mock.uploads();
+    assert_eq!(uploads.len(), 1);
+    assert!(uploads[0].iter().any(|path| path.ends_with("Dockerfile")));
+    assert!(uploads[0].iter().any(|path| path.ends_with("main.c")));
+}
+
+#[test]
+fn keeps_existing_artifacts() {
+    let output = TempDir::new().unwrap();
+    let existing = output.path().join("rpi/artifact.txt");
+    fs::create_dir_all(existing.parent().unwrap()).unwrap();
+    fs::write(&existing, "earlier artifact\n").unwrap();
+
+    let config = CONFIG.replace(
+        "  to: output\n",
+        &format!("  to: {}\n", output.path().to_string_lossy()),
+    );
+
+    let run = run_pipeline(&config, 1, |_| {});
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("Failed to copy image contents"));
+    assert_eq!(fs::read_to_string(existing).unwrap(), "earlier artifact\n");
+}
+
+#[test]
+fn passes_build_events_to_caller() {
+    let run = run_pipeline(CONFIG, 1, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert_eq!(
+        run.events[0],
+        BuildEvent::Message("Uploading source".to_string())
+    );
+    assert!(run.events.contains(&BuildEvent::Erase));
+    assert!(run.events.iter().any(|event| matches!(
+        event,
+        BuildEvent::ImagePushed { service, .. } if service == "main"
+    )));
+    assert!(run.events.contains(&BuildEvent::Unknown(
+        json!({ "type": "metadata", "resource": "phase", "value": "push" })
+    )));
+    assert_eq!(
+        run.events.last(),
+        Some(&BuildEvent::Finished {
+            success: true,
+            message: None,
+        })
+    );
+}
+
+#[test]
+fn reuses_application_and_device() {
+    let run = run_pipeline(CONFIG, 2, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications.len(), 1);
+    assert_eq!(applications[0]["app_name"], "crosser-rpi");
+    assert_eq!(applications[0]["device_type"], "raspberrypi4-64");
+
+    let devices = run.mock.resources("device");
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0]["device_name"], "rpi");
+
+    assert!(run.mock.resources("device_environment_variable").is_empty());
+
+    let credentials = read_credentials(run.project.path());
+    assert_eq!(
+        credentials["devices"][devices[0]["uuid"].as_str().unwrap()],
+        format!("key-{}", devices[0]["uuid"].as_str().unwrap())
+    );
+
+    assert_eq!(run.mock.uploads().len(), 2);
+}
+
+#[test]
+fn uses_application_name_template() {
+    let config = format!(
+        "{}project: firmware\napplication_name: ${{project}}-${{slug}}-ci\n",
+        CONFIG
+    );
+
+    let run = run_pipeline(&config, 1, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications[0]["app_name"], "firmware-rpi-ci");
+}
+
+#[test]
+fn retries_transient_api_failures() {
+    let run = run_pipeline(CONFIG, 1, |mock| mock.fail_next_requests(2));
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert_eq!(run.mock.resources("application").len(), 1);
+}
+
+fn add_organization(mock: &MockBalena, handle: &str) -> u64 {
+    mock.add_resource(
+        "organization",
+        json!({ "name": handle.to_uppercase(), "handle": handle }),
+    )
+}
+
+#[test]
+fn builds_in_organization() {
+    let config = format!("{}organization: acme\n", CONFIG);
+
+    let mut organization_id = 0;
+    let run = run_pipeline(&config, 1, |mock| {
+        organization_id = add_organization(mock, "acme");
+    });
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let organization_lookups = run.mock.queries("organization");
+    assert_eq!(organization_lookups.len(), 1);
+    assert_eq!(organization_lookups[0]["$filter"], "handle eq 'acme'");
+
+    let organization_filter = format!("organization eq {}", organization_id);
+    assert!(run
+        .mock
+        .queries("application")
+        .iter()
+        .any(|query| query["$filter"].contains(&organization_filter)));
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications.len(), 1);
+    assert_eq!(
+        applications[0]["organization"],
+        json!({ "__id": organization_id })
+    );
+
+    let builds = run.mock.builds();
+    assert_eq!(builds.len(), 1);
+    assert_eq!(builds[0]["owner"], "acme");
+}
+
+#[test]
+fn rejects_unknown_organization() {
+    let config = format!("{}organization: acme\n", CONFIG);
+
+    let run = run_pipeline(&config, 1, |mock| {
+        add_organization(mock, "other");
+    });
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("Organization 'acme' not found"));
+
+    assert!(run.mock.resources("application").is_empty());
+}
+
+#[test]
+fn passes_builder_options() {
+    let config = CONFIG.replace(
+        "    dockerfile: Dockerfile\n",
+        "    dockerfile: Dockerfile\n    build:\n      dockerfile_path: \"build dir/Dockerfile?a=1&b\"\n      emulated: true\n",
+    );
+
+    let args = CliArgs {
+        no_cache: true,
+        ..Default::default()
+    };
+
+    let run = run_pipeline_with_args(&config, |_| {}, vec![args]);
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let builds = run.mock.builds();
+    assert_eq!(builds.len(), 1);
+    assert_eq!(builds[0]["dockerfilePath"], "build dir/Dockerfile?a=1&b");
+    assert_eq!(builds[0]["emulated"], "true");
+    assert_eq!(builds[0]["nocache"], "true");
+    assert_eq!(builds[0]["headless"], "false");
+}
+
+#[test]
+fn retries_emulated_without_native_builder() {
+    let run = run_pipeline(CONFIG, 1, |mock| mock.no_native_builder());
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let emulated = run
+        .mock
+        .builds()
+        .iter()
+        .map(|build| build["emulated"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(emulated, vec!["false", "true"]);
+
+    assert!(run.project.path().join("output/rpi/artifact.txt").exists());
+}
+
+fn headless_config() -> String {
+    let config = CONFIG.replace(
+        "    dockerfile: Dockerfile\n",
+        "    dockerfile: Dockerfile\n    build:\n      headless: true\n",
+    );
+    format!("{}timeouts:\n  poll_interval_ms: 10\n", config)
+}
+
+#[test]
+fn polls_headless_build() {
+    let run = run_pipeline(&headless_config(), 1, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert_eq!(run.mock.builds()[0]["headless"], "true");
+
+    let releases = run.mock.resources("release");
+    assert_eq!(releases.len(), 1);
+    assert_eq!(releases[0]["status"], "success");
+
+    assert!(run.project.path().join("output/rpi/artifact.txt").exists());
+
+    assert!(run
+        .events
+        .contains(&BuildEvent::Message("[main]".to_string())));
+    assert_eq!(
+        run.events.last(),
+        Some(&BuildEvent::Finished {
+            success: true,
+            message: None,
+        })
+    );
+}
+
+#[test]
+fn reports_failed_headless_build() {
+    let run = run_pipeline(&headless_config(), 1, |mock| mock.fail_builds());
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Remote build for 'crosser-rpi' failed"));
+
+    assert_eq!(run.mock.resources("release")[0]["status"], "failed");
+}
+
+#[test]
+fn retries_emulated_headless_build() {
+    let run = run_pipeline(&headless_config(), 1, |mock| mock.no_native_builder());
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let emulated = run
+        .mock
+        .builds()
+        .iter()
+        .map(|build| build["emulated"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(emulated, vec!["false", "true"]);
+}
+
+#[test]
+fn reports_failed_build() {
+    let run = run_pipeline(CONFIG, 1, |mock| mock.fail_builds());
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Remote build for 'crosser-rpi' failed"));
+
+    assert!(!run.project.path().join("output/rpi/artifact.txt").exists());
+
+    let log_path = run.project.path().join("logs/rpi.log");
+    assert!(error.to_string().contains(&format!("{:?}", log_path)));
+    assert_eq!(
+        fs::read_to_string(log_path).unwrap(),
+        "Uploading source\nBuild failed\n"
+    );
+}
+
+#[test]
+fn reports_failed_build_when_build_log_cannot_be_saved() {
+    // The log directory is an existing file, so the log cannot be written
+    let config = format!("{}logs_dir: Dockerfile\n", CONFIG);
+
+    let run = run_pipeline(&config, 1, |mock| mock.fail_builds());
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Remote build for 'crosser-rpi' failed"));
+    assert!(!format!("{:#}", error).contains("build log"));
+}
+
+#[test]
+fn saves_build_log() {
+    let config = format!("{}logs_dir: build-logs\n", CONFIG);
+
+    let run = run_pipeline(&config, 1, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let log = fs::read_to_string(run.project.path().join("build-logs/rpi.log")).unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "Uploading source");
+    assert!(lines[1].starts_with("Pushed 'main' image "));
+    assert_eq!(lines[2], "Build succeeded");
+}
+
+fn add_application(mock: &MockBalena, device_type: &str, managed: bool) -> u64 {
+    let id = mock.add_resource(
+        "application",
+        json!({
+            "app_name": "crosser-rpi",
+            "device_type": device_type,
+            "user": [{ "id": 1, "username": "mock" }],
+        }),
+    );
+
+    if managed {
+        mock.add_resource(
+            "application_tag",
+            json!({
+                "application": { "__id": id },
+                "tag_key": "crosser:managed",
+                "value": "true",
+            }),
+        );
+    }
+
+    id
+}
+
+fn add_mismatched_application(mock: &MockBalena) {
+    add_application(mock, "raspberrypi3", true);
+}
+
+#[test]
+fn rejects_mismatched_device_type() {
+    let run = run_pipeline(CONFIG, 1, add_mismatched_application);
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("--recreate-mismatched"));
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications.len(), 1);
+    assert_eq!(applications[0]["device_type"], "raspberrypi3");
+    assert!(run.mock.uploads().is_empty());
+}
+
+#[test]
+fn recreates_mismatched_application() {
+    let args = CliArgs {
+        recreate_mismatched: true,
+        ..Default::default()
+    };
+    let run = run_pipeline_with_args(CONFIG, add_mismatched_application, vec![args]);
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications.len(), 1);
+    assert_eq!(applications[0]["app_name"], "crosser-rpi");
+    assert_eq!(applications[0]["device_type"], "raspberrypi4-64");
+
+    assert!(run.project.path().join("output/rpi/artifact.txt").exists());
+}
+
+fn tag_value(mock: &MockBalena, tag_key: &str) -> Option<Value> {
+    mock.resources("application_tag")
+        .into_iter()
+        .find(|tag| tag["tag_key"] == tag_key)
+        .map(|tag| tag["value"].clone())
+}
+
+#[test]
+fn tags_created_application() {
+    let run = run_pipeline(CONFIG, 1, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert_eq!(tag_value(&run.mock, "crosser:managed").unwrap(), "true");
+    assert_eq!(
+        tag_value(&run.mock, "crosser:version").unwrap(),
+        env!("CARGO_PKG_VERSION")
+    );
+    assert!(tag_value(&run.mock, "crosser:config").is_some());
+}
+
+#[test]
+fn refuses_unmanaged_application() {
+    let run = run_pipeline(CONFIG, 1, |mock| {
+        let id = add_application(mock, "raspberrypi4-64", false);
+        mock.add_resource(
+            "device",
+            json!({
+                "uuid": "production",
+                "device_name": "production",
+                "device_type": "raspberrypi4-64",
+                "belongs_to__application": { "__id": id },
+            }),
+        );
+    });
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("is not managed by crosser"));
+
+    assert_eq!(run.mock.resources("device").len(), 1);
+    assert!(run.mock.resources("application_tag").is_empty());
+    assert!(run.mock.uploads().is_empty());
+}
+
+#[test]
+fn refuses_to_recreate_unmanaged_application() {
+    let args = CliArgs {
+        recreate_mismatched: true,
+        ..Default::default()
+    };
+    let run = run_pipeline_with_args(
+        CONFIG,
+        |mock| {
+            add_application(mock, "raspberrypi3", false);
+        },
+        vec![args],
+    );
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("is not managed by crosser"));
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications.len(), 1);
+    assert_eq!(applications[0]["device_type"], "raspberrypi3");
+}
+
+#[test]
+fn cleans_managed_applications() {
+    let clean = CliArgs {
+        command: Command::Clean,
+        ..Default::default()
+    };
+    let run = run_pipeline_with_args(
+        CONFIG,
+        |mock| {
+            mock.add_resource(
+                "application",
+                json!({ "app_name": "production", "device_type": "raspberrypi4-64" }),
+            );
+        },
+        vec![CliArgs::default(), clean],
+    );
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications.len(), 1);
+    assert_eq!(applications[0]["app_name"], "production");
+    assert!(run.mock.resources("application_tag").is_empty());
+}
+
+#[test]
+fn supports_fleet_api_model() {
+    let config = format!("{}api_version: v6\n", CONFIG);
+
+    let run = run_pipeline(&config, 2, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let applications = run.mock.resources("application");
+    assert_eq!(applications.len(), 1);
+    assert_eq!(
+        applications[0]["is_for__device_type"][0]["slug"],
+        "raspberrypi4-64"
+    );
+    assert!(applications[0].get("device_type").is_none());
+
+    assert!(run.project.path().join("output/rpi/artifact.txt").exists());
+}
+
+#[test]
+fn downloads_latest_release_image() {
+    let run = run_pipeline(CONFIG, 2, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let releases = run.mock.resources("release");
+    assert_eq!(releases.len(), 2);
+    assert!(releases
+        .iter()
+        .all(|release| release["status"] == "success"));
+
+    let pulled = releases
+        .iter()
+        .map(|release| format!("v2/image{}-main", release["id"]))
+        .collect::<Vec<_>>();
+    assert_eq!(run.mock.pulls(), pulled);
+
+    assert!(run.project.path().join("output/rpi/artifact.txt").exists());
+}
+
+#[test]
+fn waits_for_device_state_release() {
+    let config = format!("{}timeouts:\n  poll_interval_ms: 10\n", CONFIG);
+
+    let run = run_pipeline(&config, 1, |mock| {
+        mock.omit_release_images();
+        mock.lag_device_state(3);
+    });
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let release = &run.mock.resources("release")[0];
+    assert_eq!(
+        run.mock.pulls(),
+        vec![format!("v2/image{}-main", release["id"])]
+    );
+
+    assert!(run.project.path().join("output/rpi/artifact.txt").exists());
+}
+
+#[test]
+fn times_out_waiting_for_device_state() {
+    let config = format!(
+        "{}timeouts:\n  device_state_secs: 0\n  poll_interval_ms: 10\n",
+        CONFIG
+    );
+
+    let run = run_pipeline(&config, 1, |mock| {
+        mock.omit_release_images();
+        mock.lag_device_state(u32::MAX);
+    });
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("did not reflect release"));
+
+    assert!(run.mock.pulls().is_empty());
+}
+
+#[test]
+fn downloads_with_user_registry_login() {
+    let config = format!("{}registry_login: user\n", CONFIG);
+
+    let run = run_pipeline(&config, 1, |_| {});
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert!(run.mock.resources("device").is_empty());
+    assert!(run.mock.resources("device_environment_variable").is_empty());
+
+    assert!(run.project.path().join("output/rpi/artifact.txt").exists());
+}
+
+#[test]
+fn migrates_api_key_from_device_variable() {
+    let run = run_pipeline(CONFIG, 1, |mock| {
+        let application = add_application(mock, "raspberrypi4-64", true);
+        let device = add_device(mock, application, "legacy");
+        mock.add_resource(
+            "device_environment_variable",
+            json!({
+                "device": { "__id": device },
+                "name": "API_KEY",
+                "value": "legacy-key",
+            }),
+        );
+    });
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    assert_eq!(run.mock.resources("device").len(), 1);
+    assert!(run.mock.resources("device_environment_variable").is_empty());
+    assert_eq!(
+        read_credentials(run.project.path())["devices"]["legacy"],
+        "legacy-key"
+    );
+}
+
+#[test]
+fn encrypts_credentials_with_passphrase() {
+    let runs = (0..2)
+        .map(|_| CliArgs {
+            credentials_passphrase: Some("secret".to_string()),
+            ..Default::default()
+        })
+        .collect();
+    let run = run_pipeline_with_args(CONFIG, |_| {}, runs);
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let devices = run.mock.resources("device");
+    assert_eq!(devices.len(), 1);
+
+    let credentials = read_credentials(run.project.path());
+    assert_eq!(credentials["format"], "encrypted");
+    assert!(!credentials
+        .to_string()
+        .contains(devices[0]["uuid"].as_str().unwrap()));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let path = run.project.path().join("credentials/credentials.json");
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+fn add_device(mock: &MockBalena, application: u64, uuid: &str) -> u64 {
+    mock.add_resource(
+        "device",
+        json!({
+            "uuid": uuid,
+            "device_name": "rpi",
+            "belongs_to__application": { "__id": application },
+        }),
+    )
+}
+
+#[test]
+fn regenerates_lost_device_api_key() {
+    let run = run_pipeline(CONFIG, 1, |mock| {
+        let application = add_application(mock, "raspberrypi4-64", true);
+        add_device(mock, application, "older");
+        add_device(mock, application, "newer");
+    });
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let devices = run.mock.resources("device");
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0]["uuid"], "newer");
+
+    let credentials = read_credentials(run.project.path());
+    assert_eq!(
+        credentials["devices"]["newer"],
+        format!("key-regenerated-{}", devices[0]["id"])
+    );
+}
+
+#[test]
+fn deletes_duplicate_devices() {
+    let run = run_pipeline(CONFIG, 1, |mock| {
+        let application = add_application(mock, "raspberrypi4-64", true);
+        let keyed = add_device(mock, application, "keyed");
+        add_device(mock, application, "duplicate");
+        mock.add_resource(
+            "device_environment_variable",
+            json!({
+                "device": { "__id": keyed },
+                "name": "API_KEY",
+                "value": "keyed-key",
+            }),
+        );
+    });
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let devices = run.mock.resources("device");
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0]["uuid"], "keyed");
+
+    let credentials = read_credentials(run.project.path());
+    assert_eq!(credentials["devices"]["keyed"], "keyed-key");
+}
+
+#[test]
+fn lists_devices_across_pages() {
+    let config = format!("{}page_size: 2\n", CONFIG);
+
+    let run = run_pipeline(&config, 1, |mock| {
+        let application = add_application(mock, "raspberrypi4-64", true);
+        for index in 1..=5 {
+            add_device(mock, application, &format!("device-{}", index));
+        }
+    });
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    // All duplicates beyond the first page are found and deleted
+    let devices = run.mock.resources("device");
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0]["uuid"], "device-5");
+
+    let queries = run.mock.queries("device");
+
+    // Paging stops at the first short page
+    assert!(queries
+        .iter()
+        .all(|query| query.get("$skip").map(String::as_str) != Some("6")));
+
+    let pages = queries
+        .into_iter()
+        .take(3)
+        .map(|query| (query["$top"].clone(), query.get("$skip").cloned()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pages,
+        vec![
+            ("2".to_string(), None),
+            ("2".to_string(), Some("2".to_string())),
+            ("2".to_string(), Some("4".to_string())),
+        ]
+    );
+}
+
+#[test]
+fn rejects_ambiguous_service_images() {
+    let run = run_pipeline(CONFIG, 1, |mock| mock.set_services(&["main", "sidecar"]));
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("Multiple services found"));
+
+    assert!(run.mock.pulls().is_empty());
+}
+
+#[test]
+fn downloads_selected_service_image() {
+    let config = CONFIG.replace(
+        "    dockerfile: Dockerfile\n",
+        "    dockerfile: Dockerfile\n    service: sidecar\n",
+    );
+
+    let run = run_pipeline(&config, 1, |mock| mock.set_services(&["main", "sidecar"]));
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let pulls = run.mock.pulls();
+    assert_eq!(pulls.len(), 1);
+    assert!(pulls[0].ends_with("-sidecar"));
+}
+
+#[test]
+fn builds_compose_project_with_service_copy_rules() {
+    let config = "
+source: src
+copy:
+  - from_image:
+      - /app/*
+    to: output
+    service: main
+  - from_image:
+      - /app/artifact.txt
+    to: sidecar-output
+    service: sidecar
+targets:
+  - slug: rpi
+    device_type: raspberrypi4-64
+    compose: services.yml
+";
+
+    let run = run_pipeline(config, 1, |mock| mock.set_services(&["main", "sidecar"]));
+
+    for result in run.results {
+        result.unwrap();
+    }
+
+    let uploads = run.mock.uploads();
+    assert!(uploads[0]
+        .iter()
+        .any(|path| path.trim_start_matches("./") == "docker-compose.yml"));
+    assert!(!uploads[0].iter().any(|path| path.ends_with("Dockerfile")));
+
+    let mut pulls = run.mock.pulls();
+    pulls.sort();
+    assert_eq!(pulls.len(), 2);
+    assert!(pulls[0].ends_with("-main"));
+    assert!(pulls[1].ends_with("-sidecar"));
+
+    let project = run.project.path();
+    assert!(project.join("output/rpi/artifact.txt").exists());
+    assert!(project.join("sidecar-output/rpi/artifact.txt").exists());
+}
+
+#[test]
+fn rejects_target_without_build_file() {
+    let config = CONFIG.replace("    dockerfile: Dockerfile\n", "");
+
+    let run = run_pipeline(&config, 1, |_| {});
+
+    let error = run.results.into_iter().next().unwrap().unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("needs either 'dockerfile' or 'compose'"));
+}